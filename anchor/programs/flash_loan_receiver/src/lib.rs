@@ -1,3 +1,5 @@
+// `executeOperation` keeps its camelCase name so callers can encode it by that name.
+#![allow(non_snake_case)]
 use anchor_lang::prelude::*;
 use anchor_spl::token_interface::{self, Mint, TokenAccount, TokenInterface, TransferChecked};
 
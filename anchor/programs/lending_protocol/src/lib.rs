@@ -1,15 +1,19 @@
 // --- 1. Imports ---
 use anchor_lang::prelude::*;
 use anchor_lang::solana_program::{
+    entrypoint::MAX_PERMITTED_DATA_INCREASE,
     instruction::{AccountMeta, Instruction},
     program::invoke,
 };
-use anchor_spl::token_interface::{self, Mint, TokenAccount, TokenInterface, TransferChecked};
+use anchor_spl::token_interface::{
+    self, Burn, CloseAccount, Mint, MintTo, TokenAccount, TokenInterface, TransferChecked,
+};
 use bytemuck::{Pod, Zeroable};
 use pyth_sdk_solana::state::SolanaPriceAccount;
-use pyth_sdk_solana::{Price, PriceFeed};
+use pyth_sdk_solana::Price;
 // Import U192 for high-precision math
 use spl_math::uint::U192;
+use std::cell::{Ref, RefMut};
 use std::mem::size_of; // <-- Added
 
 // --- 2. Program ID ---
@@ -17,21 +21,50 @@ declare_id!("2XsQQ3t5uScXfiwxWGBLNXBSMwoMfEyw9Muc1LwcC7gH");
 
 // --- 3. Constants ---
 // PDA Seeds
+const ASSET_POOL_SEED: &[u8] = b"asset_pool";
 const VAULT_SEED: &[u8] = b"asset_vault";
 const USER_POSITION_SEED: &[u8] = b"user_position";
 const MARKET_CONFIG_SEED: &[u8] = b"market_config";
 const CREDIT_DELEGATION_SEED: &[u8] = b"credit_delegation";
+const OBLIGATION_SEED: &[u8] = b"obligation";
+const SHARE_MINT_SEED: &[u8] = b"share_mint";
+const SHARE_ESCROW_SEED: &[u8] = b"share_escrow";
+const INSURANCE_VAULT_SEED: &[u8] = b"insurance_vault";
+const PENDING_PARAM_CHANGE_SEED: &[u8] = b"pending_param_change";
+const PENDING_LIQUIDATION_CONFIG_SEED: &[u8] = b"pending_liquidation_config";
 
 // Financial Parameters
-const LIQUIDATION_BONUS_BPS: u128 = 500; // 5%
-const MAX_LIQUIDATION_RATIO_BPS: u128 = 5000; // 50%
-const STALE_PRICE_THRESHOLD_SECONDS: u64 = 60;
 const SECONDS_IN_YEAR: u128 = 31_536_000;
 const BASIS_POINTS_DIVISOR: u128 = 10_000;
-// Constant for scaling high-precision math results back to u128
-const PRECISION_DIVISOR: u128 = 1_000_000_000_000; // 10^12
-                                                   // Oracle Security Parameter
-const MAX_CONFIDENCE_INTERVAL_BPS: u64 = 300; // 3%
+// Fixed-point scale of the cumulative borrow and supply indices (1.0 == 10^18)
+const INDEX_PRECISION: u128 = 1_000_000_000_000_000_000;
+// Asset values are expressed in the quote currency with this many decimals
+const VALUE_DECIMALS: u32 = 12;
+// Accounts per obligation leg on refresh: [asset_pool, user_position, pyth_feed, chainlink_feed]
+const ACCOUNTS_PER_OBLIGATION_LEG: usize = 4;
+// Accounts per deposit leg seized by `socialize_bad_debt`: [asset_pool, user_position, share_mint, share_escrow]
+const ACCOUNTS_PER_SEIZED_LEG: usize = 4;
+// Per-pool pause flags, one bit per action in `AssetPool.paused_actions`
+const PAUSE_DEPOSIT: u8 = 1 << 0;
+const PAUSE_WITHDRAW: u8 = 1 << 1;
+const PAUSE_BORROW: u8 = 1 << 2;
+const PAUSE_REPAY: u8 = 1 << 3;
+const PAUSE_LIQUIDATE: u8 = 1 << 4;
+const PAUSE_FLASH_LOAN: u8 = 1 << 5;
+const PAUSE_ALL: u8 = PAUSE_DEPOSIT
+    | PAUSE_WITHDRAW
+    | PAUSE_BORROW
+    | PAUSE_REPAY
+    | PAUSE_LIQUIDATE
+    | PAUSE_FLASH_LOAN;
+// Actions a reduce-only (delisting) pool rejects because they grow its deposits or loans
+const REDUCE_ONLY_BLOCKED_ACTIONS: u8 = PAUSE_DEPOSIT | PAUSE_BORROW | PAUSE_FLASH_LOAN;
+// Amount sentinel: `withdraw` takes the most that keeps the obligation healthy, `repay` the whole debt
+const AMOUNT_ALL: u64 = u64::MAX;
+// Largest change a risk admin may make to a risk, close-factor or rate parameter in one update
+const RISK_ADMIN_MAX_STEP_BPS: u64 = 500;
+// Longest timelock governance may set; keeps queued changes executable
+const MAX_PARAM_CHANGE_DELAY_SECONDS: i64 = 30 * 24 * 60 * 60;
 
 // --- 4. Program Module ---
 #[program]
@@ -40,25 +73,57 @@ pub mod lending_protocol {
 
     // --- Governance Instructions ---
 
-    /// [Governance] Initializes the central market configuration.
-    pub fn initialize_market_config(ctx: Context<InitializeMarketConfig>) -> Result<()> {
+    /// [Governance] Initializes a market, identified by its creator and `market_id`.
+    ///
+    /// Markets are isolated from each other: every pool and obligation belongs to one market.
+    pub fn initialize_market_config(
+        ctx: Context<InitializeMarketConfig>,
+        market_id: u64,
+    ) -> Result<()> {
         let config = &mut ctx.accounts.market_config.load_init()?;
         config.governance_authority = ctx.accounts.owner.key();
         config.status = 0; // 0 = Active
+        config.creator = ctx.accounts.owner.key();
+        config.market_id = market_id;
         emit!(MarketConfigInitialized {
+            market: ctx.accounts.market_config.key(),
+            market_id,
             new_governance_authority: ctx.accounts.owner.key()
         });
         Ok(())
     }
 
-    /// [Governance] Transfers governance authority to a new public key.
-    pub fn update_governance_authority(
+    /// [Governance] Proposes a new governance authority, which takes over once it accepts.
+    pub fn propose_governance_authority(
         ctx: Context<UpdateGovernanceAuthority>,
         new_authority: Pubkey,
     ) -> Result<()> {
+        require_keys_neq!(
+            new_authority,
+            Pubkey::default(),
+            LendingError::InvalidPendingAuthority
+        );
+        let config = &mut ctx.accounts.market_config.load_mut()?;
+        config.pending_governance_authority = new_authority;
+        emit!(GovernanceAuthorityProposed {
+            current_authority: config.governance_authority,
+            proposed_authority: new_authority,
+        });
+        Ok(())
+    }
+
+    /// [Pending Governance] Accepts a proposed transfer, becoming the governance authority.
+    pub fn accept_governance_authority(ctx: Context<AcceptGovernanceAuthority>) -> Result<()> {
         let config = &mut ctx.accounts.market_config.load_mut()?;
+        let new_authority = ctx.accounts.pending_governance_authority.key();
+        require_keys_eq!(
+            config.pending_governance_authority,
+            new_authority,
+            LendingError::InvalidPendingAuthority
+        );
         let old_authority = config.governance_authority;
         config.governance_authority = new_authority;
+        config.pending_governance_authority = Pubkey::default();
         emit!(GovernanceAuthorityChanged {
             old_authority,
             new_authority,
@@ -66,9 +131,55 @@ pub mod lending_protocol {
         Ok(())
     }
 
-    /// [Governance] Pauses the protocol, halting most user interactions.
-    pub fn pause_protocol(ctx: Context<UpdateGovernanceAuthority>) -> Result<()> {
-        ctx.accounts.market_config.load_mut()?.status = 1; // 1 = Paused
+    /// [Governance] Withdraws a governance authority proposal that has not been accepted.
+    pub fn cancel_governance_authority_transfer(
+        ctx: Context<UpdateGovernanceAuthority>,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.market_config.load_mut()?;
+        let cancelled_authority = config.pending_governance_authority;
+        require_keys_neq!(
+            cancelled_authority,
+            Pubkey::default(),
+            LendingError::NoPendingAuthority
+        );
+        config.pending_governance_authority = Pubkey::default();
+        emit!(GovernanceAuthorityTransferCancelled {
+            cancelled_authority
+        });
+        Ok(())
+    }
+
+    /// [Governance] Assigns a market role; the default key revokes it.
+    pub fn set_market_role(
+        ctx: Context<UpdateGovernanceAuthority>,
+        role: MarketRole,
+        authority: Pubkey,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.market_config.load_mut()?;
+        let slot = match role {
+            MarketRole::Guardian => &mut config.guardian,
+            MarketRole::RiskAdmin => &mut config.risk_admin,
+            MarketRole::FeeCollector => &mut config.fee_collector,
+            MarketRole::FeeTreasury => &mut config.fee_treasury,
+        };
+        let old_authority = *slot;
+        *slot = authority;
+        emit!(MarketRoleUpdated {
+            role,
+            old_authority,
+            new_authority: authority,
+        });
+        Ok(())
+    }
+
+    /// [Governance/Guardian] Pauses the protocol, halting most user interactions.
+    pub fn pause_protocol(ctx: Context<GuardianAction>) -> Result<()> {
+        let config = &mut ctx.accounts.market_config.load_mut()?;
+        require!(
+            config.is_governance_or(config.guardian, &ctx.accounts.authority.key()),
+            LendingError::Unauthorized
+        );
+        config.status = 1; // 1 = Paused
         emit!(ProtocolPaused {});
         Ok(())
     }
@@ -80,34 +191,167 @@ pub mod lending_protocol {
         Ok(())
     }
 
-    /// [Governance] Sets the protocol to withdraw-only mode.
-    pub fn enable_withdraw_only_mode(ctx: Context<UpdateGovernanceAuthority>) -> Result<()> {
-        ctx.accounts.market_config.load_mut()?.status = 2; // 2 = WithdrawOnly
+    /// [Governance/Guardian] Sets the protocol to withdraw-only mode.
+    pub fn enable_withdraw_only_mode(ctx: Context<GuardianAction>) -> Result<()> {
+        let config = &mut ctx.accounts.market_config.load_mut()?;
+        require!(
+            config.is_governance_or(config.guardian, &ctx.accounts.authority.key()),
+            LendingError::Unauthorized
+        );
+        config.status = 2; // 2 = WithdrawOnly
         emit!(ProtocolWithdrawOnlyModeEnabled {});
         Ok(())
     }
 
+    /// [Governance] Sets when a liquidation may repay the whole debt instead of the close factor.
+    ///
+    /// Obligations borrowing less than `dust_value` (quote units, `VALUE_DECIMALS` decimals) or
+    /// with a health factor below `full_liquidation_health_factor_bps` can be fully liquidated.
+    /// Zero disables either rule. Raising either one exposes more borrowers to full
+    /// liquidation, so once a delay is configured it must go through
+    /// `queue_liquidation_config_update`.
+    pub fn update_liquidation_config(
+        ctx: Context<UpdateGovernanceAuthority>,
+        dust_value: u128,
+        full_liquidation_health_factor_bps: u64,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.market_config.load_mut()?;
+        require!(
+            config.param_change_delay == 0
+                || (dust_value <= config.liquidation_dust_value
+                    && full_liquidation_health_factor_bps
+                        <= config.full_liquidation_health_factor_bps),
+            LendingError::TimelockRequired
+        );
+        config.set_liquidation_config(dust_value, full_liquidation_health_factor_bps)?;
+        emit!(LiquidationConfigUpdated {
+            dust_value,
+            full_liquidation_health_factor_bps,
+        });
+        Ok(())
+    }
+
+    /// [Governance] Queues a new liquidation config, executable once the delay has passed.
+    pub fn queue_liquidation_config_update(
+        ctx: Context<QueueLiquidationConfigUpdate>,
+        dust_value: u128,
+        full_liquidation_health_factor_bps: u64,
+    ) -> Result<()> {
+        // Reject invalid values now rather than at execution time
+        let mut preview = *ctx.accounts.market_config.load()?;
+        preview.set_liquidation_config(dust_value, full_liquidation_health_factor_bps)?;
+
+        let eta = Clock::get()?
+            .unix_timestamp
+            .checked_add(preview.param_change_delay)
+            .ok_or(LendingError::MathOverflow)?;
+        let pending = &mut ctx.accounts.pending_liquidation_config;
+        pending.market = ctx.accounts.market_config.key();
+        pending.dust_value = dust_value;
+        pending.full_liquidation_health_factor_bps = full_liquidation_health_factor_bps;
+        pending.eta = eta;
+        emit!(LiquidationConfigChangeQueued {
+            market: pending.market,
+            eta,
+        });
+        Ok(())
+    }
+
+    /// [Governance] Applies a queued liquidation config whose delay has passed.
+    pub fn execute_liquidation_config_update(
+        ctx: Context<ResolveLiquidationConfigUpdate>,
+    ) -> Result<()> {
+        let pending = &ctx.accounts.pending_liquidation_config;
+        require!(
+            Clock::get()?.unix_timestamp >= pending.eta,
+            LendingError::TimelockNotElapsed
+        );
+        ctx.accounts
+            .market_config
+            .load_mut()?
+            .set_liquidation_config(
+                pending.dust_value,
+                pending.full_liquidation_health_factor_bps,
+            )?;
+        emit!(LiquidationConfigUpdated {
+            dust_value: pending.dust_value,
+            full_liquidation_health_factor_bps: pending.full_liquidation_health_factor_bps,
+        });
+        Ok(())
+    }
+
+    /// [Governance] Drops a queued liquidation config without applying it.
+    pub fn cancel_liquidation_config_update(
+        ctx: Context<ResolveLiquidationConfigUpdate>,
+    ) -> Result<()> {
+        emit!(LiquidationConfigChangeCancelled {
+            market: ctx.accounts.market_config.key(),
+        });
+        Ok(())
+    }
+
     /// [Governance] Adds a new asset pool to the market.
-    pub fn add_asset_pool(ctx: Context<AddAssetPool>, params: AssetPoolParams) -> Result<()> {
+    ///
+    /// The pool lives at the PDA `[ASSET_POOL_SEED, market, mint]`, so a market holds at most
+    /// one pool per mint and clients can derive it from the mint alone. `primary_oracle` and
+    /// `fallback_oracle` name the provider behind the primary and fallback feed accounts;
+    /// `pyth_feed_id` is required when either of them is `PythPull`.
+    ///
+    /// Once a delay is configured, a new pool's collateral backs nothing: LTV and liquidation
+    /// threshold start at zero and can only be raised through `queue_asset_pool_update`, so
+    /// a pool listed with a bad price feed cannot be borrowed against before the delay passes.
+    ///
+    /// A mint whose pool was removed can be listed again at the same address. The share mint
+    /// outlives the old pool, so it is reused as long as no shares of it remain.
+    pub fn add_asset_pool(
+        ctx: Context<AddAssetPool>,
+        params: AssetPoolParams,
+        primary_oracle: OracleSource,
+        fallback_oracle: OracleSource,
+        pyth_feed_id: [u8; 32],
+    ) -> Result<()> {
+        let uses_pyth_pull =
+            primary_oracle == OracleSource::PythPull || fallback_oracle == OracleSource::PythPull;
+        require!(
+            !uses_pyth_pull || pyth_feed_id != [0u8; 32],
+            LendingError::MissingPythFeedId
+        );
+        require!(
+            ctx.accounts.share_mint.supply == 0,
+            LendingError::PoolNotEmpty
+        );
+
         let asset_pool_key = ctx.accounts.asset_pool.key();
         let pool = &mut ctx.accounts.asset_pool.load_init()?;
 
+        pool.market = ctx.accounts.market_config.key();
         pool.asset_mint = ctx.accounts.asset_mint.key();
+        pool.asset_decimals = ctx.accounts.asset_mint.decimals;
         pool.asset_vault = ctx.accounts.asset_vault.key();
+        pool.share_mint = ctx.accounts.share_mint.key();
+        pool.insurance_vault = ctx.accounts.insurance_vault.key();
         pool.pyth_price_feed = ctx.accounts.pyth_price_feed_account.key();
 
         pool.chainlink_price_feed = match ctx.accounts.chainlink_price_feed_account.as_ref() {
             Some(account) => account.key(),
             None => anchor_lang::system_program::ID, // 如果没有提供，就存入哨兵值
         };
+        pool.primary_oracle = primary_oracle as u8;
+        pool.fallback_oracle = fallback_oracle as u8;
+        pool.pyth_feed_id = pyth_feed_id;
 
         pool.apply_params(params)?;
+        if ctx.accounts.market_config.load()?.param_change_delay > 0 {
+            pool.loan_to_value_bps = 0;
+            pool.liquidation_threshold_bps = 0;
+        }
+        pool.cumulative_borrow_index = INDEX_PRECISION;
+        pool.cumulative_supply_index = INDEX_PRECISION;
         pool.last_interest_update_timestamp = Clock::get()?.unix_timestamp;
 
-        ctx.accounts
-            .market_config
-            .load_mut()?
-            .add_pool(asset_pool_key)?;
+        let market_info = ctx.accounts.market_config.to_account_info();
+        let (mut market_config, mut overflow) = MarketConfig::load_registry_mut(&market_info)?;
+        market_config.add_pool(&mut overflow, asset_pool_key)?;
 
         emit!(AssetPoolAdded {
             pool_key: asset_pool_key,
@@ -116,17 +360,235 @@ pub mod lending_protocol {
         Ok(())
     }
 
-    /// [Governance] Updates the parameters for an existing asset pool.
+    /// [Governance] Adds `additional_pools` slots to the market's pool registry.
+    ///
+    /// The slots are appended after `MarketConfig`, so the market keeps its address and state.
+    /// A market created under an older, shorter layout is first grown to the current one, with
+    /// the new fields zeroed; `additional_pools` may then be zero.
+    ///
+    /// An account can grow by at most `MAX_PERMITTED_DATA_INCREASE` bytes per instruction, so
+    /// one call adds about 320 slots (fewer while migrating); larger growth takes several calls.
+    pub fn grow_pool_registry(ctx: Context<GrowPoolRegistry>, additional_pools: u16) -> Result<()> {
+        let info = ctx.accounts.market_config.to_account_info();
+        {
+            let data = info.try_borrow_data()?;
+            require!(
+                data.len() >= 8 + size_of::<Pubkey>() && data[..8] == *MarketConfig::DISCRIMINATOR,
+                ErrorCode::AccountDiscriminatorMismatch
+            );
+            // `governance_authority` leads every MarketConfig layout
+            let governance_authority: Pubkey = bytemuck::pod_read_unaligned(&data[8..40]);
+            require_keys_eq!(
+                governance_authority,
+                ctx.accounts.governance_authority.key(),
+                LendingError::Unauthorized
+            );
+        }
+
+        let old_len = info.data_len();
+        let new_len =
+            old_len.max(MarketConfig::LEN) + additional_pools as usize * size_of::<Pubkey>();
+        require!(new_len > old_len, LendingError::ZeroAmount);
+        require!(
+            new_len - old_len <= MAX_PERMITTED_DATA_INCREASE,
+            LendingError::RegistryGrowthTooLarge
+        );
+        let capacity =
+            MarketConfig::INLINE_POOLS + (new_len - MarketConfig::LEN) / size_of::<Pubkey>();
+        require!(
+            capacity <= u16::MAX as usize,
+            LendingError::MaxAssetsExceeded
+        );
+
+        let rent_due = Rent::get()?
+            .minimum_balance(new_len)
+            .saturating_sub(info.lamports());
+        if rent_due > 0 {
+            anchor_lang::system_program::transfer(
+                CpiContext::new(
+                    ctx.accounts.system_program.to_account_info(),
+                    anchor_lang::system_program::Transfer {
+                        from: ctx.accounts.governance_authority.to_account_info(),
+                        to: info.clone(),
+                    },
+                ),
+                rent_due,
+            )?;
+        }
+        info.realloc(new_len, true)?;
+
+        emit!(PoolRegistryGrown {
+            market: info.key(),
+            capacity: capacity as u16,
+        });
+        Ok(())
+    }
+
+    /// [Governance/Risk Admin] Updates the parameters for an existing asset pool.
+    ///
+    /// Applies immediately only if the change lowers risk or no delay is configured;
+    /// anything else must go through `queue_asset_pool_update`. The risk admin cannot touch
+    /// fees or loosen caps, and moves risk, close-factor and rate parameters by at most
+    /// `RISK_ADMIN_MAX_STEP_BPS`.
     pub fn update_asset_pool(ctx: Context<UpdateAssetPool>, params: AssetPoolParams) -> Result<()> {
-        ctx.accounts.asset_pool.load_mut()?.apply_params(params)?;
+        let config = ctx.accounts.market_config.load()?;
+        let authority = ctx.accounts.authority.key();
+        let param_change_delay = config.param_change_delay;
+        let pool = &mut ctx.accounts.asset_pool.load_mut()?;
+        if authority != config.governance_authority {
+            require!(
+                config.is_governance_or(config.risk_admin, &authority)
+                    && pool.is_within_risk_admin_bounds(&params),
+                LendingError::Unauthorized
+            );
+        }
+        require!(
+            param_change_delay == 0 || pool.is_risk_reducing(&params),
+            LendingError::TimelockRequired
+        );
+        pool.apply_params(params)?;
         emit!(AssetPoolUpdated {
-            pool_key: ctx.accounts.asset_pool.key()
+            pool_key: ctx.accounts.asset_pool.key(),
+            liquidation_bonus_bps: pool.liquidation_bonus_bps,
+            close_factor_bps: pool.close_factor_bps,
+            insurance_fee_bps: pool.insurance_fee_bps,
+            supply_cap: pool.supply_cap,
+            borrow_cap: pool.borrow_cap,
+            max_price_age_seconds: pool.max_price_age_seconds,
+            max_confidence_bps: pool.max_confidence_bps,
+            max_oracle_deviation_bps: pool.max_oracle_deviation_bps,
+            conservative_pricing: pool.is_conservative_pricing(),
+        });
+        Ok(())
+    }
+
+    /// [Governance] Raises the minimum delay between queuing and executing a parameter change.
+    ///
+    /// The delay can never be lowered, so a compromised authority cannot shorten it, and is
+    /// capped at `MAX_PARAM_CHANGE_DELAY_SECONDS` so queued changes stay executable.
+    pub fn update_param_change_delay(
+        ctx: Context<UpdateGovernanceAuthority>,
+        delay_seconds: i64,
+    ) -> Result<()> {
+        let config = &mut ctx.accounts.market_config.load_mut()?;
+        let old_delay_seconds = config.param_change_delay;
+        require!(
+            delay_seconds >= old_delay_seconds && delay_seconds <= MAX_PARAM_CHANGE_DELAY_SECONDS,
+            LendingError::InvalidParamChangeDelay
+        );
+        config.param_change_delay = delay_seconds;
+        emit!(ParamChangeDelayUpdated {
+            old_delay_seconds,
+            new_delay_seconds: delay_seconds,
+        });
+        Ok(())
+    }
+
+    /// [Governance] Queues new parameters for an asset pool, executable once the delay has passed.
+    pub fn queue_asset_pool_update(
+        ctx: Context<QueueAssetPoolUpdate>,
+        params: AssetPoolParams,
+    ) -> Result<()> {
+        // Reject invalid parameters now rather than at execution time
+        let mut preview = *ctx.accounts.asset_pool.load()?;
+        preview.apply_params(params.clone())?;
+
+        let eta = Clock::get()?
+            .unix_timestamp
+            .checked_add(ctx.accounts.market_config.load()?.param_change_delay)
+            .ok_or(LendingError::MathOverflow)?;
+        let pending = &mut ctx.accounts.pending_param_change;
+        pending.asset_pool = ctx.accounts.asset_pool.key();
+        pending.params = params;
+        pending.eta = eta;
+        emit!(ParamChangeQueued {
+            pool: pending.asset_pool,
+            eta,
         });
         Ok(())
     }
 
-    /// [Governance] Collects accrued protocol fees from an asset pool.
+    /// [Governance] Applies a queued parameter change whose delay has passed.
+    pub fn execute_asset_pool_update(ctx: Context<ResolveAssetPoolUpdate>) -> Result<()> {
+        let pending = &ctx.accounts.pending_param_change;
+        require!(
+            Clock::get()?.unix_timestamp >= pending.eta,
+            LendingError::TimelockNotElapsed
+        );
+        let pool = &mut ctx.accounts.asset_pool.load_mut()?;
+        pool.apply_params(pending.params.clone())?;
+        emit!(AssetPoolUpdated {
+            pool_key: ctx.accounts.asset_pool.key(),
+            liquidation_bonus_bps: pool.liquidation_bonus_bps,
+            close_factor_bps: pool.close_factor_bps,
+            insurance_fee_bps: pool.insurance_fee_bps,
+            supply_cap: pool.supply_cap,
+            borrow_cap: pool.borrow_cap,
+            max_price_age_seconds: pool.max_price_age_seconds,
+            max_confidence_bps: pool.max_confidence_bps,
+            max_oracle_deviation_bps: pool.max_oracle_deviation_bps,
+            conservative_pricing: pool.is_conservative_pricing(),
+        });
+        Ok(())
+    }
+
+    /// [Governance] Discards a queued parameter change.
+    pub fn cancel_asset_pool_update(ctx: Context<ResolveAssetPoolUpdate>) -> Result<()> {
+        emit!(ParamChangeCancelled {
+            pool: ctx.accounts.asset_pool.key(),
+        });
+        Ok(())
+    }
+
+    /// [Governance/Guardian] Pauses individual actions on a single asset pool.
+    ///
+    /// `paused_actions` is a bitmask: deposit = 1, withdraw = 2, borrow = 4, repay = 8,
+    /// liquidate = 16, flash loan = 32. Zero resumes every action. The guardian can only
+    /// add flags; resuming an action is left to governance.
+    pub fn set_pool_pause_flags(ctx: Context<SetPoolPauseFlags>, paused_actions: u8) -> Result<()> {
+        require!(
+            paused_actions & !PAUSE_ALL == 0,
+            LendingError::InvalidPauseFlags
+        );
+        let config = ctx.accounts.market_config.load()?;
+        let authority = ctx.accounts.authority.key();
+        let pool = &mut ctx.accounts.asset_pool.load_mut()?;
+        let old_paused_actions = pool.paused_actions;
+        if authority != config.governance_authority {
+            require!(
+                config.is_governance_or(config.guardian, &authority)
+                    && paused_actions & old_paused_actions == old_paused_actions,
+                LendingError::Unauthorized
+            );
+        }
+        pool.paused_actions = paused_actions;
+        emit!(PoolPauseFlagsChanged {
+            pool: ctx.accounts.asset_pool.key(),
+            old_paused_actions,
+            new_paused_actions: paused_actions,
+        });
+        Ok(())
+    }
+
+    /// [Governance/Fee Collector] Collects accrued protocol fees from an asset pool.
+    ///
+    /// The fee collector can only pay out to a token account owned by the fee treasury.
     pub fn collect_protocol_fees(ctx: Context<CollectProtocolFees>) -> Result<()> {
+        let config = ctx.accounts.market_config.load()?;
+        let authority = ctx.accounts.authority.key();
+        if authority != config.governance_authority {
+            require!(
+                config.is_governance_or(config.fee_collector, &authority),
+                LendingError::Unauthorized
+            );
+            let fee_treasury = config.fee_treasury;
+            require!(
+                fee_treasury != Pubkey::default()
+                    && ctx.accounts.destination_account.owner == fee_treasury,
+                LendingError::InvalidFeeTreasury
+            );
+        }
+        drop(config);
         let pool = &mut ctx.accounts.asset_pool.load_mut()?;
         let fees_to_collect = pool.accrued_protocol_fees;
         require_gt!(fees_to_collect, 0, LendingError::ZeroAmount);
@@ -155,26 +617,296 @@ pub mod lending_protocol {
         Ok(())
     }
 
+    /// [Anyone] Moves the insurance share of accrued interest into the pool's insurance vault.
+    pub fn fund_insurance_reserve(ctx: Context<FundInsuranceReserve>) -> Result<()> {
+        let pool = &mut ctx.accounts.asset_pool.load_mut()?;
+        pool.accrue_interest()?;
+        let amount = pool.accrued_insurance_fees;
+        require_gt!(amount, 0, LendingError::ZeroAmount);
+
+        pool.accrued_insurance_fees = 0;
+
+        let pool_key = ctx.accounts.asset_pool.key();
+        let seeds = &[VAULT_SEED, pool_key.as_ref(), &[ctx.bumps.asset_vault]];
+        cpi_utils::transfer_from_vault_checked(
+            &ctx.accounts.asset_vault.to_account_info(),
+            &ctx.accounts.insurance_vault.to_account_info(),
+            &ctx.accounts.asset_mint.to_account_info(),
+            &ctx.accounts.asset_vault.to_account_info(),
+            &ctx.accounts.token_program.to_account_info(),
+            &[&seeds[..]],
+            amount,
+            ctx.accounts.asset_mint.decimals,
+        )?;
+
+        emit!(InsuranceReserveFunded {
+            pool: pool_key,
+            amount,
+        });
+        Ok(())
+    }
+
+    /// [Governance] Withdraws from a pool's insurance reserve.
+    ///
+    /// The reserve backs outstanding loans, so it can only be withdrawn once the pool has none.
+    pub fn withdraw_insurance_reserve(
+        ctx: Context<WithdrawInsuranceReserve>,
+        amount: u64,
+    ) -> Result<()> {
+        require_gt!(amount, 0, LendingError::ZeroAmount);
+        let pool = &mut ctx.accounts.asset_pool.load_mut()?;
+        pool.accrue_interest()?;
+        require!(
+            pool.total_scaled_loans == 0,
+            LendingError::InsuranceReserveLocked
+        );
+
+        let pool_key = ctx.accounts.asset_pool.key();
+        let seeds = &[
+            INSURANCE_VAULT_SEED,
+            pool_key.as_ref(),
+            &[ctx.bumps.insurance_vault],
+        ];
+        cpi_utils::transfer_from_vault_checked(
+            &ctx.accounts.insurance_vault.to_account_info(),
+            &ctx.accounts.destination_account.to_account_info(),
+            &ctx.accounts.asset_mint.to_account_info(),
+            &ctx.accounts.insurance_vault.to_account_info(),
+            &ctx.accounts.token_program.to_account_info(),
+            &[&seeds[..]],
+            amount,
+            ctx.accounts.asset_mint.decimals,
+        )?;
+
+        emit!(InsuranceReserveWithdrawn {
+            pool: pool_key,
+            recipient: ctx.accounts.destination_account.key(),
+            amount,
+        });
+        Ok(())
+    }
+
+    /// [Governance] Puts a pool into reduce-only mode as the first step of delisting it.
+    ///
+    /// Deposits, borrows and flash loans are rejected from then on; withdrawals, repayments and
+    /// liquidations keep working so positions can unwind. There is no way back to active.
+    pub fn delist_asset_pool(ctx: Context<DelistAssetPool>) -> Result<()> {
+        let pool = &mut ctx.accounts.asset_pool.load_mut()?;
+        require!(!pool.is_reduce_only(), LendingError::PoolReduceOnly);
+        pool.status = 1; // 1 = ReduceOnly
+        emit!(AssetPoolDelisted {
+            pool: ctx.accounts.asset_pool.key(),
+        });
+        Ok(())
+    }
+
+    /// [Governance] Removes an emptied reduce-only pool from the market.
+    ///
+    /// The pool counts as empty once every share is burned and no position owes anything.
+    /// Shares track scaled collateral one to one, so a zero share supply means every depositor
+    /// has been paid out, even though `total_deposits` keeps the rounding dust withdrawals
+    /// leave behind. Debt is checked the same way through `total_scaled_loans`, since
+    /// `total_loans` can reach zero while rounded-up debts are still open; removing the pool
+    /// then would strand those borrow legs.
+    ///
+    /// Sweeps whatever is left in the asset and insurance vaults (uncollected fees, rounding
+    /// dust) to `destination_account`, closes the pool's token accounts and the pool itself,
+    /// returning their rent to governance, and frees its slot in `MarketConfig.pools`. The
+    /// share mint cannot be closed; it stays behind with zero supply for a later relisting.
+    pub fn remove_asset_pool(ctx: Context<RemoveAssetPool>) -> Result<()> {
+        let pool_key = ctx.accounts.asset_pool.key();
+        let pool = ctx.accounts.asset_pool.load()?;
+        require!(pool.is_reduce_only(), LendingError::PoolNotReduceOnly);
+        require!(
+            ctx.accounts.share_mint.supply == 0 && pool.total_scaled_loans == 0,
+            LendingError::PoolNotEmpty
+        );
+        let asset_mint = pool.asset_mint;
+        drop(pool);
+
+        let token_program = ctx.accounts.token_program.to_account_info();
+        let destination = ctx.accounts.destination_account.to_account_info();
+        let rent_receiver = ctx.accounts.governance_authority.to_account_info();
+        let mint = ctx.accounts.asset_mint.to_account_info();
+        let decimals = ctx.accounts.asset_mint.decimals;
+
+        let vault_seeds = &[VAULT_SEED, pool_key.as_ref(), &[ctx.bumps.asset_vault]];
+        let insurance_seeds = &[
+            INSURANCE_VAULT_SEED,
+            pool_key.as_ref(),
+            &[ctx.bumps.insurance_vault],
+        ];
+        let escrow_seeds = &[
+            SHARE_ESCROW_SEED,
+            pool_key.as_ref(),
+            &[ctx.bumps.share_escrow],
+        ];
+
+        let mut swept_amount: u64 = 0;
+        for (vault, seeds) in [
+            (&ctx.accounts.asset_vault, &vault_seeds[..]),
+            (&ctx.accounts.insurance_vault, &insurance_seeds[..]),
+        ] {
+            let vault_info = vault.to_account_info();
+            if vault.amount > 0 {
+                cpi_utils::transfer_from_vault_checked(
+                    &vault_info,
+                    &destination,
+                    &mint,
+                    &vault_info,
+                    &token_program,
+                    &[seeds],
+                    vault.amount,
+                    decimals,
+                )?;
+                swept_amount = swept_amount
+                    .checked_add(vault.amount)
+                    .ok_or(LendingError::MathOverflow)?;
+            }
+            cpi_utils::close_vault(&vault_info, &rent_receiver, &token_program, &[seeds])?;
+        }
+        cpi_utils::close_vault(
+            &ctx.accounts.share_escrow.to_account_info(),
+            &rent_receiver,
+            &token_program,
+            &[&escrow_seeds[..]],
+        )?;
+
+        let market_info = ctx.accounts.market_config.to_account_info();
+        let (mut market_config, mut overflow) = MarketConfig::load_registry_mut(&market_info)?;
+        market_config.remove_pool(&mut overflow, pool_key)?;
+
+        emit!(AssetPoolRemoved {
+            pool: pool_key,
+            asset_mint,
+            swept_amount,
+        });
+        Ok(())
+    }
+
     // --- Core Lending Instructions ---
 
     /// [User] Initializes a user's position account for a specific asset pool.
+    ///
+    /// Optional before a deposit: `deposit` and `execute_operations` create it on first use.
     pub fn create_user_position(ctx: Context<CreateUserPosition>) -> Result<()> {
         let position = &mut ctx.accounts.user_position.load_init()?;
         position.owner = ctx.accounts.user.key();
         position.pool = ctx.accounts.asset_pool.key();
-        position.collateral_amount = 0;
-        position.loan_amount = 0;
+        position.scaled_collateral = 0;
+        position.scaled_loan = 0;
+        Ok(())
+    }
+
+    /// [User] Closes an empty position account, returning its rent to the owner.
+    ///
+    /// Works even after the pool has been removed; an obligation leg still pointing at the
+    /// position simply values it as zero.
+    pub fn close_user_position(ctx: Context<CloseUserPosition>) -> Result<()> {
+        let position = ctx.accounts.user_position.load()?;
+        require!(
+            position.scaled_collateral == 0 && position.scaled_loan == 0,
+            LendingError::PositionNotEmpty
+        );
+        Ok(())
+    }
+
+    /// [User] Initializes the obligation that aggregates a user's positions across pools.
+    pub fn create_obligation(ctx: Context<CreateObligation>) -> Result<()> {
+        let obligation = &mut ctx.accounts.obligation.load_init()?;
+        obligation.owner = ctx.accounts.user.key();
+        obligation.market = ctx.accounts.market_config.key();
+        Ok(())
+    }
+
+    /// [User] Adds a market pool to the obligation's deposit or borrow legs.
+    pub fn open_obligation_leg(
+        ctx: Context<OpenObligationLeg>,
+        side: ObligationLegSide,
+    ) -> Result<()> {
+        let pool_key = ctx.accounts.asset_pool.key();
+        let market_info = ctx.accounts.market_config.to_account_info();
+        let (market_config, overflow) = MarketConfig::load_registry(&market_info)?;
+        require!(
+            market_config.is_listed(&overflow, &pool_key),
+            LendingError::InvalidAssetPool
+        );
+
+        ctx.accounts
+            .obligation
+            .load_mut()?
+            .open_leg(side, pool_key)?;
+
+        emit!(ObligationLegChanged {
+            owner: ctx.accounts.user.key(),
+            pool: pool_key,
+            side,
+            open: true
+        });
+        Ok(())
+    }
+
+    /// [User] Removes a pool from the obligation. The position's balance on that side must be zero.
+    pub fn close_obligation_leg(
+        ctx: Context<CloseObligationLeg>,
+        side: ObligationLegSide,
+    ) -> Result<()> {
+        let pool_key = ctx.accounts.asset_pool.key();
+        // A position that was never created holds no balance on either side
+        if !ctx.accounts.user_position.data_is_empty() {
+            let position: UserPosition = utils::read_zero_copy(&ctx.accounts.user_position)?;
+            let balance = match side {
+                ObligationLegSide::Deposit => position.scaled_collateral,
+                ObligationLegSide::Borrow => position.scaled_loan,
+            };
+            require!(balance == 0, LendingError::ObligationLegNotEmpty);
+        }
+
+        let obligation = &mut ctx.accounts.obligation.load_mut()?;
+        obligation.close_leg(side, pool_key)?;
+        obligation.mark_stale();
+
+        emit!(ObligationLegChanged {
+            owner: ctx.accounts.user.key(),
+            pool: pool_key,
+            side,
+            open: false
+        });
+        Ok(())
+    }
+
+    /// [Anyone] Revalues an obligation at current prices and interest indices.
+    ///
+    /// Remaining accounts: one `[asset_pool, user_position, pyth_feed, chainlink_feed]` group per
+    /// deposit leg, then one per borrow leg, in leg order.
+    pub fn refresh_obligation(ctx: Context<RefreshObligation>) -> Result<()> {
+        let obligation = &mut ctx.accounts.obligation.load_mut()?;
+        let portfolio = utils::value_obligation(obligation, ctx.remaining_accounts)?;
+        obligation.set_value(&portfolio);
+        obligation.last_update_slot = Clock::get()?.slot;
         Ok(())
     }
 
     /// [User] Deposits assets into a pool to be used as collateral.
+    ///
+    /// Pool shares are minted at the current exchange rate into the share escrow and
+    /// credited to the user's position; `unlock_collateral` releases them to the wallet.
     pub fn deposit(ctx: Context<Deposit>, amount: u64) -> Result<()> {
         require!(
             ctx.accounts.market_config.load()?.status == 0,
             LendingError::ProtocolNotActive
         );
         require_gt!(amount, 0, LendingError::ZeroAmount);
-        ctx.accounts.asset_pool.load_mut()?.accrue_interest()?;
+        UserPosition::init_if_new(
+            &ctx.accounts.user_position,
+            ctx.accounts.user.key(),
+            ctx.accounts.asset_pool.key(),
+        )?;
+        {
+            let pool = &mut ctx.accounts.asset_pool.load_mut()?;
+            pool.require_action_enabled(PAUSE_DEPOSIT)?;
+            pool.accrue_interest()?;
+        }
 
         cpi_utils::transfer_from_user_checked(
             &ctx.accounts.user.to_account_info(),
@@ -186,17 +918,26 @@ pub mod lending_protocol {
             ctx.accounts.asset_mint.decimals,
         )?;
 
+        let pool = &mut ctx.accounts.asset_pool.load_mut()?;
+        let scaled_amount = utils::to_scaled(amount, pool.cumulative_supply_index, false)?;
+
+        let pool_key = ctx.accounts.asset_pool.key();
+        let seeds = &[SHARE_MINT_SEED, pool_key.as_ref(), &[ctx.bumps.share_mint]];
+        cpi_utils::mint_shares(
+            &ctx.accounts.share_mint.to_account_info(),
+            &ctx.accounts.share_escrow.to_account_info(),
+            &ctx.accounts.token_program.to_account_info(),
+            &[&seeds[..]],
+            scaled_amount,
+        )?;
+
         let user_position = &mut ctx.accounts.user_position.load_mut()?;
-        user_position.collateral_amount = user_position
-            .collateral_amount
-            .checked_add(amount)
+        user_position.scaled_collateral = user_position
+            .scaled_collateral
+            .checked_add(scaled_amount)
             .ok_or(LendingError::MathOverflow)?;
 
-        let pool = &mut ctx.accounts.asset_pool.load_mut()?;
-        pool.total_deposits = pool
-            .total_deposits
-            .checked_add(amount)
-            .ok_or(LendingError::MathOverflow)?;
+        pool.add_deposits(amount)?;
 
         emit!(Deposited {
             pool: ctx.accounts.asset_pool.key(),
@@ -207,6 +948,9 @@ pub mod lending_protocol {
     }
 
     /// [User] Withdraws collateral from a pool, subject to health checks.
+    ///
+    /// If the pool is a deposit leg, the obligation must be refreshed in the same slot.
+    /// `AMOUNT_ALL` (`u64::MAX`) withdraws the most that keeps the obligation healthy.
     pub fn withdraw(ctx: Context<Withdraw>, amount: u64) -> Result<()> {
         let market_config = ctx.accounts.market_config.load()?;
         require!(
@@ -215,29 +959,41 @@ pub mod lending_protocol {
         );
         require_gt!(amount, 0, LendingError::ZeroAmount);
         let mut pool = ctx.accounts.asset_pool.load_mut()?;
+        pool.require_action_enabled(PAUSE_WITHDRAW)?;
         pool.accrue_interest()?;
 
-        let user_position = &mut ctx.accounts.user_position.load_mut()?;
-        let new_collateral_amount = user_position
-            .collateral_amount
-            .checked_sub(amount)
-            .ok_or(LendingError::InsufficientCollateralAmount)?;
-
+        require_keys_eq!(
+            ctx.accounts.pyth_price_feed_account.key(),
+            pool.pyth_price_feed,
+            LendingError::InvalidOracleAccount
+        );
+        require_keys_eq!(
+            ctx.accounts.chainlink_price_feed_account.key(),
+            pool.chainlink_price_feed,
+            LendingError::InvalidOracleAccount
+        );
         let price = oracle::get_price(
             &ctx.accounts.pyth_price_feed_account,
             Option::from(ctx.accounts.chainlink_price_feed_account.as_ref()),
+            pool.oracle_config()?,
         )?;
+
+        let pool_key = ctx.accounts.asset_pool.key();
+        let user_position = &mut ctx.accounts.user_position.load_mut()?;
+        let collateral = pool.collateral_from_scaled(user_position.scaled_collateral)?;
+        let obligation = &mut ctx.accounts.obligation.load_mut()?;
+        let amount = if amount == AMOUNT_ALL {
+            obligation.max_withdraw(&pool_key, &pool, collateral, price)?
+        } else {
+            amount
+        };
+        require_gt!(amount, 0, LendingError::ZeroAmount);
         require!(
-            utils::is_healthy(
-                new_collateral_amount,
-                user_position.loan_amount,
-                price,
-                pool.liquidation_threshold_bps
-            )?,
-            LendingError::PositionWouldBecomeUnhealthy
+            collateral >= amount,
+            LendingError::InsufficientCollateralAmount
         );
+        obligation.apply_withdraw(&pool_key, &pool, amount, price)?;
 
-        let pool_key = ctx.accounts.asset_pool.key();
         let seeds = &[VAULT_SEED, pool_key.as_ref(), &[ctx.bumps.asset_vault]];
 
         cpi_utils::transfer_from_vault_checked(
@@ -251,7 +1007,29 @@ pub mod lending_protocol {
             ctx.accounts.asset_mint.decimals,
         )?;
 
-        user_position.collateral_amount = new_collateral_amount;
+        // Rounding up keeps the burned scaled balance from ever being worth less than `amount`;
+        // withdrawing everything burns the whole balance so no scaled dust is left behind.
+        let scaled_amount = if amount == collateral {
+            user_position.scaled_collateral
+        } else {
+            utils::to_scaled(amount, pool.cumulative_supply_index, true)?
+        };
+        let escrow_seeds = &[
+            SHARE_ESCROW_SEED,
+            pool_key.as_ref(),
+            &[ctx.bumps.share_escrow],
+        ];
+        cpi_utils::burn_shares(
+            &ctx.accounts.share_mint.to_account_info(),
+            &ctx.accounts.share_escrow.to_account_info(),
+            &ctx.accounts.token_program.to_account_info(),
+            &[&escrow_seeds[..]],
+            scaled_amount,
+        )?;
+        user_position.scaled_collateral = user_position
+            .scaled_collateral
+            .checked_sub(scaled_amount)
+            .ok_or(LendingError::InsufficientCollateralAmount)?;
         pool.total_deposits = pool
             .total_deposits
             .checked_sub(amount)
@@ -265,9 +1043,118 @@ pub mod lending_protocol {
         Ok(())
     }
 
-    /// [User] Borrows assets against their deposited collateral.
-    // in lending-protocol/src/lib.rs
+    /// [User] Pledges pool shares held in the user's wallet as collateral in their position.
+    pub fn lock_collateral(ctx: Context<LockCollateral>, shares: u64) -> Result<()> {
+        require!(
+            ctx.accounts.market_config.load()?.status == 0,
+            LendingError::ProtocolNotActive
+        );
+        require_gt!(shares, 0, LendingError::ZeroAmount);
+        ctx.accounts
+            .asset_pool
+            .load()?
+            .require_action_enabled(PAUSE_DEPOSIT)?;
 
+        cpi_utils::transfer_from_user_checked(
+            &ctx.accounts.user.to_account_info(),
+            &ctx.accounts.user_share_account.to_account_info(),
+            &ctx.accounts.share_escrow.to_account_info(),
+            &ctx.accounts.share_mint.to_account_info(),
+            &ctx.accounts.token_program.to_account_info(),
+            shares,
+            ctx.accounts.share_mint.decimals,
+        )?;
+
+        let user_position = &mut ctx.accounts.user_position.load_mut()?;
+        user_position.scaled_collateral = user_position
+            .scaled_collateral
+            .checked_add(shares)
+            .ok_or(LendingError::MathOverflow)?;
+
+        emit!(CollateralLocked {
+            pool: ctx.accounts.asset_pool.key(),
+            user: ctx.accounts.user.key(),
+            shares
+        });
+        Ok(())
+    }
+
+    /// [User] Releases collateral from the position as transferable pool shares, subject to health checks.
+    ///
+    /// If the pool is a deposit leg, the obligation must be refreshed in the same slot.
+    pub fn unlock_collateral(ctx: Context<UnlockCollateral>, shares: u64) -> Result<()> {
+        let market_config = ctx.accounts.market_config.load()?;
+        require!(
+            market_config.status == 0 || market_config.status == 2, // Active or WithdrawOnly
+            LendingError::ProtocolPaused
+        );
+        require_gt!(shares, 0, LendingError::ZeroAmount);
+        let mut pool = ctx.accounts.asset_pool.load_mut()?;
+        pool.require_action_enabled(PAUSE_WITHDRAW)?;
+        pool.accrue_interest()?;
+
+        let user_position = &mut ctx.accounts.user_position.load_mut()?;
+        require!(
+            user_position.scaled_collateral >= shares,
+            LendingError::InsufficientCollateralAmount
+        );
+
+        require_keys_eq!(
+            ctx.accounts.pyth_price_feed_account.key(),
+            pool.pyth_price_feed,
+            LendingError::InvalidOracleAccount
+        );
+        require_keys_eq!(
+            ctx.accounts.chainlink_price_feed_account.key(),
+            pool.chainlink_price_feed,
+            LendingError::InvalidOracleAccount
+        );
+        let price = oracle::get_price(
+            &ctx.accounts.pyth_price_feed_account,
+            Option::from(ctx.accounts.chainlink_price_feed_account.as_ref()),
+            pool.oracle_config()?,
+        )?;
+
+        let pool_key = ctx.accounts.asset_pool.key();
+        // Round up so the released shares are never valued below what they can redeem
+        let amount = utils::from_scaled(shares, pool.cumulative_supply_index, true)?;
+        ctx.accounts
+            .obligation
+            .load_mut()?
+            .apply_withdraw(&pool_key, &pool, amount, price)?;
+
+        let seeds = &[
+            SHARE_ESCROW_SEED,
+            pool_key.as_ref(),
+            &[ctx.bumps.share_escrow],
+        ];
+        cpi_utils::transfer_from_vault_checked(
+            &ctx.accounts.share_escrow.to_account_info(),
+            &ctx.accounts.user_share_account.to_account_info(),
+            &ctx.accounts.share_mint.to_account_info(),
+            &ctx.accounts.share_escrow.to_account_info(),
+            &ctx.accounts.token_program.to_account_info(),
+            &[&seeds[..]],
+            shares,
+            ctx.accounts.share_mint.decimals,
+        )?;
+
+        user_position.scaled_collateral = user_position
+            .scaled_collateral
+            .checked_sub(shares)
+            .ok_or(LendingError::InsufficientCollateralAmount)?;
+
+        emit!(CollateralUnlocked {
+            pool: pool_key,
+            user: ctx.accounts.user.key(),
+            shares
+        });
+        Ok(())
+    }
+
+    /// [User] Borrows assets against the collateral in their obligation's deposit legs.
+    ///
+    /// The pool must be a borrow leg and the obligation must be refreshed in the same slot.
     pub fn borrow(ctx: Context<Borrow>, amount: u64) -> Result<()> {
         // We load mutable versions at the beginning since we'll need them for updates later.
         // This also performs an implicit "account exists" check.
@@ -324,6 +1211,7 @@ pub mod lending_protocol {
 
         // a. Check protocol status
         require!(market_config.status == 0, LendingError::ProtocolNotActive);
+        pool.require_action_enabled(PAUSE_BORROW)?;
         msg!("Step 2.a: Protocol status check passed (status is active).");
 
         // b. Check amount
@@ -340,7 +1228,8 @@ pub mod lending_protocol {
         }
 
         // d. Calculate new loan amount (we already have a mutable `user_position`)
-        let new_loan_amount = match user_position.loan_amount.checked_add(amount) {
+        let current_loan_amount = pool.debt_from_scaled(user_position.scaled_loan)?;
+        let new_loan_amount = match current_loan_amount.checked_add(amount) {
             Some(val) => val,
             None => {
                 msg!("Error: MathOverflow during new_loan_amount calculation.");
@@ -357,6 +1246,7 @@ pub mod lending_protocol {
         let price = match oracle::get_price(
             &ctx.accounts.pyth_price_feed_account,
             Option::from(ctx.accounts.chainlink_price_feed_account.as_ref()), // Pass as Option<&AccountInfo>
+            pool.oracle_config()?,
         ) {
             Ok(p) => p,
             Err(e) => {
@@ -373,23 +1263,18 @@ pub mod lending_protocol {
             price.price
         );
 
-        // f. Check eligibility for borrow
+        // f. Check eligibility for borrow against the obligation
         msg!("Step 2.f: About to check is_eligible_for_borrow.");
-        require!(
-            utils::is_eligible_for_borrow(
-                user_position.collateral_amount,
-                new_loan_amount,
-                price,
-                pool.loan_to_value_bps
-            )?,
-            LendingError::InsufficientCollateral
-        );
+        let pool_key = ctx.accounts.asset_pool.key();
+        ctx.accounts
+            .obligation
+            .load_mut()?
+            .apply_borrow(&pool_key, &pool, amount, price)?;
         msg!("Step 2.f: Eligibility check passed.");
 
         // --- 3. CPI and State Updates ---
         msg!("--- Performing CPI transfer and state updates ---");
 
-        let pool_key = ctx.accounts.asset_pool.key();
         let seeds = &[VAULT_SEED, pool_key.as_ref(), &[ctx.bumps.asset_vault]];
 
         cpi_utils::transfer_from_vault_checked(
@@ -405,11 +1290,13 @@ pub mod lending_protocol {
         )?;
         msg!("CPI transfer successful.");
 
-        user_position.loan_amount = new_loan_amount;
-        pool.total_loans = pool
-            .total_loans
-            .checked_add(amount)
+        // Debt is scaled rounding up so the borrower never owes less than they received.
+        let scaled_amount = utils::to_scaled(amount, pool.cumulative_borrow_index, true)?;
+        user_position.scaled_loan = user_position
+            .scaled_loan
+            .checked_add(scaled_amount)
             .ok_or(LendingError::MathOverflow)?;
+        pool.add_loans(amount, scaled_amount)?;
         msg!("State updated successfully.");
 
         emit!(Borrowed {
@@ -421,7 +1308,7 @@ pub mod lending_protocol {
         msg!("--- 'borrow' instruction finished successfully ---");
         Ok(())
     }
-    /// [User] Repays a loan to the asset pool.
+    /// [User] Repays a loan to the asset pool. `AMOUNT_ALL` (`u64::MAX`) repays the whole debt.
     pub fn repay(ctx: Context<Repay>, amount: u64) -> Result<()> {
         let market_config = ctx.accounts.market_config.load()?;
         require!(
@@ -429,10 +1316,14 @@ pub mod lending_protocol {
             LendingError::ProtocolPaused
         );
         require_gt!(amount, 0, LendingError::ZeroAmount);
-        ctx.accounts.asset_pool.load_mut()?.accrue_interest()?;
+        let pool = &mut ctx.accounts.asset_pool.load_mut()?;
+        pool.require_action_enabled(PAUSE_REPAY)?;
+        pool.accrue_interest()?;
 
         let user_position = &mut ctx.accounts.user_position.load_mut()?;
-        let actual_repayment = amount.min(user_position.loan_amount);
+        let current_debt = pool.debt_from_scaled(user_position.scaled_loan)?;
+        // `AMOUNT_ALL` (or any amount above the debt) settles it exactly
+        let actual_repayment = amount.min(current_debt);
 
         if actual_repayment == 0 {
             return Ok(());
@@ -448,16 +1339,16 @@ pub mod lending_protocol {
             ctx.accounts.asset_mint.decimals,
         )?;
 
-        user_position.loan_amount = user_position
-            .loan_amount
-            .checked_sub(actual_repayment)
-            .ok_or(LendingError::MathOverflow)?;
-
-        let pool = &mut ctx.accounts.asset_pool.load_mut()?;
-        pool.total_loans = pool
-            .total_loans
-            .checked_sub(actual_repayment)
+        let scaled_amount = if actual_repayment == current_debt {
+            user_position.scaled_loan
+        } else {
+            utils::to_scaled(actual_repayment, pool.cumulative_borrow_index, false)?
+        };
+        user_position.scaled_loan = user_position
+            .scaled_loan
+            .checked_sub(scaled_amount)
             .ok_or(LendingError::MathOverflow)?;
+        pool.remove_loans(actual_repayment, scaled_amount)?;
 
         emit!(Repaid {
             pool: ctx.accounts.asset_pool.key(),
@@ -468,6 +1359,8 @@ pub mod lending_protocol {
     }
 
     /// [Liquidator] Liquidates an unhealthy position by repaying debt to seize collateral.
+    ///
+    /// The borrower's obligation must be refreshed in the same slot.
     pub fn liquidate(ctx: Context<Liquidate>, amount_to_repay: u64) -> Result<()> {
         // --- 1. Load accounts once at the beginning ---
         let collateral_pool = ctx.accounts.collateral_pool.load()?;
@@ -541,48 +1434,84 @@ pub mod lending_protocol {
 
         // --- 3. Instruction Logic ---
         require!(market_config.status == 0, LendingError::ProtocolNotActive);
+        collateral_pool.require_action_enabled(PAUSE_LIQUIDATE)?;
+        loan_pool.require_action_enabled(PAUSE_LIQUIDATE)?;
         require_keys_neq!(
             ctx.accounts.liquidator.key(),
             ctx.accounts.borrower.key(),
             LendingError::CannotLiquidateSelf
         );
+        {
+            // Only collateral pledged to the obligation can be seized for its debt
+            let obligation = ctx.accounts.obligation.load()?;
+            require!(
+                obligation.has_leg(
+                    ObligationLegSide::Deposit,
+                    &ctx.accounts.collateral_pool.key()
+                ) && obligation.has_leg(ObligationLegSide::Borrow, &ctx.accounts.loan_pool.key()),
+                LendingError::ObligationLegNotFound
+            );
+        }
 
-        // Now load mutable versions for updates
-        let mut collateral_pool_mut = ctx.accounts.collateral_pool.load_mut()?;
-        let mut loan_pool_mut = ctx.accounts.loan_pool.load_mut()?;
-        collateral_pool_mut.accrue_interest()?;
-        loan_pool_mut.accrue_interest()?;
+        // Release the read borrows before the pools are loaded mutably below
+        drop(collateral_pool);
+        drop(loan_pool);
+        drop(borrower_collateral_pos);
+        drop(borrower_loan_pos);
+        drop(market_config);
+
+        // Accrue interest so both legs are valued at up-to-date indices
+        ctx.accounts.collateral_pool.load_mut()?.accrue_interest()?;
+        ctx.accounts.loan_pool.load_mut()?.accrue_interest()?;
 
         require!(
             ctx.accounts.is_liquidatable()?,
             LendingError::PositionHealthy
         );
 
-        // Reloading is necessary because accrue_interest modifies them
-        let borrower_loan_amount = ctx.accounts.borrower_loan_position.load()?.loan_amount;
+        // Reloading is necessary because accrue_interest modifies the indices
+        let borrower_loan_amount = ctx
+            .accounts
+            .loan_pool
+            .load()?
+            .debt_from_scaled(ctx.accounts.borrower_loan_position.load()?.scaled_loan)?;
         let borrower_collateral_amount = ctx
             .accounts
-            .borrower_collateral_position
+            .collateral_pool
             .load()?
-            .collateral_amount;
-
+            .collateral_from_scaled(
+                ctx.accounts
+                    .borrower_collateral_position
+                    .load()?
+                    .scaled_collateral,
+            )?;
+
+        let close_factor_bps = ctx.accounts.close_factor_bps()?;
         let max_repay_amount = U192::from(borrower_loan_amount)
-            .checked_mul(U192::from(MAX_LIQUIDATION_RATIO_BPS))
+            .checked_mul(U192::from(close_factor_bps))
             .and_then(|v| v.checked_div(U192::from(BASIS_POINTS_DIVISOR)))
             .map(|v| v.as_u64())
             .ok_or(LendingError::MathOverflow)?;
-        let actual_repay_amount = amount_to_repay
+        let mut actual_repay_amount = amount_to_repay
             .min(max_repay_amount)
             .min(borrower_loan_amount);
         require_gt!(actual_repay_amount, 0, LendingError::ZeroAmount);
 
-        let collateral_to_liquidator_amount = ctx
+        let mut collateral_to_liquidator_amount = ctx
             .accounts
             .calculate_liquidation_bonus(actual_repay_amount)?;
-        require!(
-            collateral_to_liquidator_amount <= borrower_collateral_amount,
-            LendingError::InsufficientCollateralForLiquidation
-        );
+        if collateral_to_liquidator_amount > borrower_collateral_amount {
+            // Underwater leg: seize all of it and repay only what it covers with the bonus
+            collateral_to_liquidator_amount = borrower_collateral_amount;
+            actual_repay_amount = ctx
+                .accounts
+                .calculate_repay_for_collateral(borrower_collateral_amount)?;
+            require_gt!(
+                actual_repay_amount,
+                0,
+                LendingError::InsufficientCollateralForLiquidation
+            );
+        }
 
         cpi_utils::transfer_from_user_checked(
             &ctx.accounts.liquidator.to_account_info(),
@@ -611,27 +1540,61 @@ pub mod lending_protocol {
             ctx.accounts.collateral_mint.decimals,
         )?;
 
-        let mut borrower_loan_position = ctx.accounts.borrower_loan_position.load_mut()?;
-        borrower_loan_position.loan_amount = borrower_loan_position
-            .loan_amount
-            .checked_sub(actual_repay_amount)
-            .ok_or(LendingError::MathOverflow)?;
-
-        let mut borrower_collateral_position =
-            ctx.accounts.borrower_collateral_position.load_mut()?;
-        borrower_collateral_position.collateral_amount = borrower_collateral_position
-            .collateral_amount
-            .checked_sub(collateral_to_liquidator_amount)
-            .ok_or(LendingError::MathOverflow)?;
-
-        loan_pool_mut.total_loans = loan_pool_mut
-            .total_loans
-            .checked_sub(actual_repay_amount)
-            .ok_or(LendingError::MathOverflow)?;
-        collateral_pool_mut.total_deposits = collateral_pool_mut
-            .total_deposits
-            .checked_sub(collateral_to_liquidator_amount)
-            .ok_or(LendingError::MathOverflow)?;
+        {
+            let mut loan_pool = ctx.accounts.loan_pool.load_mut()?;
+            let mut borrower_loan_position = ctx.accounts.borrower_loan_position.load_mut()?;
+            let scaled_amount = if actual_repay_amount == borrower_loan_amount {
+                borrower_loan_position.scaled_loan
+            } else {
+                utils::to_scaled(
+                    actual_repay_amount,
+                    loan_pool.cumulative_borrow_index,
+                    false,
+                )?
+            };
+            borrower_loan_position.scaled_loan = borrower_loan_position
+                .scaled_loan
+                .checked_sub(scaled_amount)
+                .ok_or(LendingError::MathOverflow)?;
+            loan_pool.remove_loans(actual_repay_amount, scaled_amount)?;
+        }
+        {
+            let mut collateral_pool = ctx.accounts.collateral_pool.load_mut()?;
+            let mut borrower_collateral_position =
+                ctx.accounts.borrower_collateral_position.load_mut()?;
+            // Seizing the whole leg burns the whole balance, as a full withdrawal does
+            let scaled_amount = if collateral_to_liquidator_amount == borrower_collateral_amount {
+                borrower_collateral_position.scaled_collateral
+            } else {
+                utils::to_scaled(
+                    collateral_to_liquidator_amount,
+                    collateral_pool.cumulative_supply_index,
+                    true,
+                )?
+            };
+            let escrow_seeds = &[
+                SHARE_ESCROW_SEED,
+                collateral_pool_key.as_ref(),
+                &[ctx.bumps.collateral_share_escrow],
+            ];
+            cpi_utils::burn_shares(
+                &ctx.accounts.collateral_share_mint.to_account_info(),
+                &ctx.accounts.collateral_share_escrow.to_account_info(),
+                &ctx.accounts.token_program.to_account_info(),
+                &[&escrow_seeds[..]],
+                scaled_amount,
+            )?;
+            borrower_collateral_position.scaled_collateral = borrower_collateral_position
+                .scaled_collateral
+                .checked_sub(scaled_amount)
+                .ok_or(LendingError::MathOverflow)?;
+            collateral_pool.total_deposits = collateral_pool
+                .total_deposits
+                .checked_sub(collateral_to_liquidator_amount)
+                .ok_or(LendingError::MathOverflow)?;
+        }
+        // Both legs changed; the borrower must be refreshed before it is checked again
+        ctx.accounts.obligation.load_mut()?.mark_stale();
 
         emit!(Liquidation {
             collateral_pool: ctx.accounts.collateral_pool.key(),
@@ -644,6 +1607,95 @@ pub mod lending_protocol {
         Ok(())
     }
 
+    /// [Anyone] Writes off a borrow leg of an obligation whose collateral is exhausted.
+    ///
+    /// `liquidate` seizes collateral until what is left is worth less than a unit of the loan
+    /// token. An unhealthy obligation whose collateral is worth less than the market's
+    /// `liquidation_dust_value` (or nothing at all) counts as exhausted: its remaining
+    /// collateral is seized here, burning the borrower's shares and crediting the tokens to
+    /// the collateral pool's protocol fees. The debt is then absorbed, first by the loan
+    /// pool's insurance reserve, then by its accrued protocol fees, and finally by lowering
+    /// the supply index so that every supplier takes a pro-rata loss.
+    ///
+    /// `remaining_accounts` holds one `[asset_pool, user_position, share_mint, share_escrow]`
+    /// group per deposit leg, in leg order. The obligation must be refreshed in the same slot.
+    pub fn socialize_bad_debt<'info>(
+        ctx: Context<'_, '_, 'info, 'info, SocializeBadDebt<'info>>,
+    ) -> Result<()> {
+        let market_config = ctx.accounts.market_config.load()?;
+        require!(market_config.status == 0, LendingError::ProtocolNotActive);
+        let dust_value = market_config.liquidation_dust_value;
+        drop(market_config);
+        let loan_pool_key = ctx.accounts.loan_pool.key();
+        let deposit_legs = {
+            let obligation = ctx.accounts.obligation.load()?;
+            obligation.require_fresh()?;
+            require!(
+                obligation.has_leg(ObligationLegSide::Borrow, &loan_pool_key),
+                LendingError::ObligationLegNotFound
+            );
+            let portfolio = obligation.value();
+            require!(
+                portfolio.deposited_value == 0
+                    || (portfolio.deposited_value < dust_value && !utils::is_healthy(&portfolio)),
+                LendingError::CollateralNotExhausted
+            );
+            obligation.legs(ObligationLegSide::Deposit)
+        };
+        ctx.accounts
+            .seize_remaining_collateral(&deposit_legs, ctx.remaining_accounts)?;
+
+        let mut loan_pool = ctx.accounts.loan_pool.load_mut()?;
+        loan_pool.require_action_enabled(PAUSE_LIQUIDATE)?;
+        loan_pool.accrue_interest()?;
+        let mut borrower_loan_position = ctx.accounts.borrower_loan_position.load_mut()?;
+        let bad_debt = loan_pool.debt_from_scaled(borrower_loan_position.scaled_loan)?;
+        require_gt!(bad_debt, 0, LendingError::ZeroAmount);
+
+        loan_pool.remove_loans(bad_debt, borrower_loan_position.scaled_loan)?;
+        borrower_loan_position.scaled_loan = 0;
+        let accrued_insurance_fees = loan_pool.accrued_insurance_fees;
+        let (covered_by_reserve, covered_by_fees, socialized) =
+            loan_pool.absorb_bad_debt(bad_debt, ctx.accounts.insurance_vault.amount)?;
+        let supply_index = loan_pool.cumulative_supply_index;
+        drop(borrower_loan_position);
+        drop(loan_pool);
+        ctx.accounts.obligation.load_mut()?.mark_stale();
+
+        // Unfunded insurance fees are only a claim on the pool, so writing them down moves no
+        // tokens. Whatever the funded reserve covers is paid from the insurance vault back into
+        // the loan vault, in place of the repayment the borrower will never make.
+        let drawn_from_vault = covered_by_reserve.saturating_sub(accrued_insurance_fees);
+        if drawn_from_vault > 0 {
+            let seeds = &[
+                INSURANCE_VAULT_SEED,
+                loan_pool_key.as_ref(),
+                &[ctx.bumps.insurance_vault],
+            ];
+            cpi_utils::transfer_from_vault_checked(
+                &ctx.accounts.insurance_vault.to_account_info(),
+                &ctx.accounts.loan_vault.to_account_info(),
+                &ctx.accounts.loan_mint.to_account_info(),
+                &ctx.accounts.insurance_vault.to_account_info(),
+                &ctx.accounts.token_program.to_account_info(),
+                &[&seeds[..]],
+                drawn_from_vault,
+                ctx.accounts.loan_mint.decimals,
+            )?;
+        }
+
+        emit!(BadDebtSocialized {
+            pool: loan_pool_key,
+            borrower: ctx.accounts.borrower.key(),
+            bad_debt,
+            covered_by_reserve,
+            covered_by_fees,
+            socialized,
+            supply_index,
+        });
+        Ok(())
+    }
+
     /// [User/Bot] Executes a flash loan.
     pub fn flash_loan(
         ctx: Context<FlashLoan>,
@@ -655,6 +1707,7 @@ pub mod lending_protocol {
             LendingError::ProtocolNotActive
         );
         let mut pool = ctx.accounts.asset_pool.load_mut()?;
+        pool.require_action_enabled(PAUSE_FLASH_LOAN)?;
         require_gt!(amount, 0, LendingError::ZeroAmount);
         require_gt!(
             pool.flash_loan_fee_bps,
@@ -720,15 +1773,13 @@ pub mod lending_protocol {
             LendingError::FlashLoanRepaymentFailed
         );
 
+        // The fee belongs to the protocol; crediting it to `total_deposits` as well would make
+        // the pool total drift away from the suppliers' indexed balances.
         let actual_fee_earned = vault_balance_after.saturating_sub(vault_balance_before);
         pool.accrued_protocol_fees = pool
             .accrued_protocol_fees
             .checked_add(actual_fee_earned)
             .ok_or(LendingError::MathOverflow)?;
-        pool.total_deposits = pool
-            .total_deposits
-            .checked_add(actual_fee_earned)
-            .ok_or(LendingError::MathOverflow)?;
 
         emit!(FlashLoaned {
             pool: pool_key,
@@ -794,6 +1845,8 @@ pub mod lending_protocol {
     }
 
     /// [Delegatee] Borrows using the credit line delegated to them.
+    ///
+    /// The pool must be a borrow leg of the owner's obligation, refreshed in the same slot.
     pub fn borrow_delegated(ctx: Context<BorrowDelegated>, amount: u64) -> Result<()> {
         // --- 1. Load all accounts mutably at the beginning ---
         let market_config = ctx.accounts.market_config.load()?;
@@ -851,6 +1904,7 @@ pub mod lending_protocol {
 
         // --- 3. Instruction Logic ---
         require!(market_config.status == 0, LendingError::ProtocolNotActive);
+        pool.require_action_enabled(PAUSE_BORROW)?;
         require_gt!(amount, 0, LendingError::ZeroAmount);
         require!(
             amount <= credit_delegation.delegated_amount,
@@ -859,27 +1913,18 @@ pub mod lending_protocol {
 
         pool.accrue_interest()?;
 
-        let new_loan_amount = owner_position
-            .loan_amount
-            .checked_add(amount)
-            .ok_or(LendingError::MathOverflow)?;
-
         let price = oracle::get_price(
             &ctx.accounts.pyth_price_feed_account,
             Option::from(ctx.accounts.chainlink_price_feed_account.as_ref()),
+            pool.oracle_config()?,
         )?;
 
-        require!(
-            utils::is_eligible_for_borrow(
-                owner_position.collateral_amount,
-                new_loan_amount,
-                price,
-                pool.loan_to_value_bps
-            )?,
-            LendingError::InsufficientCollateral
-        );
-
         let pool_key = ctx.accounts.asset_pool.key();
+        ctx.accounts
+            .obligation
+            .load_mut()?
+            .apply_borrow(&pool_key, &pool, amount, price)?;
+
         let seeds = &[VAULT_SEED, pool_key.as_ref(), &[ctx.bumps.asset_vault]];
 
         cpi_utils::transfer_from_vault_checked(
@@ -897,11 +1942,12 @@ pub mod lending_protocol {
             .delegated_amount
             .checked_sub(amount)
             .ok_or(LendingError::MathOverflow)?;
-        owner_position.loan_amount = new_loan_amount;
-        pool.total_loans = pool
-            .total_loans
-            .checked_add(amount)
+        let scaled_amount = utils::to_scaled(amount, pool.cumulative_borrow_index, true)?;
+        owner_position.scaled_loan = owner_position
+            .scaled_loan
+            .checked_add(scaled_amount)
             .ok_or(LendingError::MathOverflow)?;
+        pool.add_loans(amount, scaled_amount)?;
 
         emit!(BorrowedDelegated {
             pool: ctx.accounts.asset_pool.key(),
@@ -913,34 +1959,56 @@ pub mod lending_protocol {
         Ok(())
     }
     /// [User] Executes a batch of operations in a single transaction.
+    ///
+    /// Withdraw and Borrow operations are checked against the obligation, as in `withdraw` and `borrow`.
+    /// Withdraw and Repay accept `AMOUNT_ALL` with the same meaning as the standalone instructions.
     pub fn execute_operations(
         ctx: Context<ExecuteOperations>,
         operations: Vec<Operation>,
     ) -> Result<()> {
-        require!(
-            ctx.accounts.market_config.load()?.status == 0,
-            LendingError::ProtocolNotActive
-        );
+        let market_config = ctx.accounts.market_config.load()?;
+        require!(market_config.status == 0, LendingError::ProtocolNotActive);
+        UserPosition::init_if_new(
+            &ctx.accounts.user_position,
+            ctx.accounts.user.key(),
+            ctx.accounts.asset_pool.key(),
+        )?;
         let mut pool = ctx.accounts.asset_pool.load_mut()?;
         let mut position = ctx.accounts.user_position.load_mut()?;
         pool.accrue_interest()?;
 
-        let mut current_collateral = position.collateral_amount;
-        let mut current_debt = position.loan_amount;
+        let mut scaled_collateral = position.scaled_collateral;
+        let mut scaled_loan = position.scaled_loan;
         let pool_key = ctx.accounts.asset_pool.key();
         let seeds = &[VAULT_SEED, pool_key.as_ref(), &[ctx.bumps.asset_vault]];
+        let share_mint_seeds = &[SHARE_MINT_SEED, pool_key.as_ref(), &[ctx.bumps.share_mint]];
+        let share_escrow_seeds = &[
+            SHARE_ESCROW_SEED,
+            pool_key.as_ref(),
+            &[ctx.bumps.share_escrow],
+        ];
 
         let asset_mint_info = ctx.accounts.asset_mint.to_account_info();
+        let share_mint_info = ctx.accounts.share_mint.to_account_info();
+        let share_escrow_info = ctx.accounts.share_escrow.to_account_info();
         let asset_decimals = ctx.accounts.asset_mint.decimals;
+        require_keys_eq!(
+            ctx.accounts.pyth_price_feed_account.key(),
+            pool.pyth_price_feed,
+            LendingError::InvalidOracleAccount
+        );
         let price = oracle::get_price(
             &ctx.accounts.pyth_price_feed_account,
             Option::from(&ctx.accounts.chainlink_price_feed_account),
+            pool.oracle_config()?,
         )?;
+        let mut obligation = ctx.accounts.obligation.load_mut()?;
 
         for op in operations {
             match op {
                 Operation::Deposit { amount } => {
                     require_gt!(amount, 0, LendingError::InvalidOperation);
+                    pool.require_action_enabled(PAUSE_DEPOSIT)?;
                     cpi_utils::transfer_from_user_checked(
                         &ctx.accounts.user.to_account_info(),
                         &ctx.accounts.user_asset_account.to_account_info(),
@@ -950,28 +2018,33 @@ pub mod lending_protocol {
                         amount,
                         asset_decimals,
                     )?;
-                    current_collateral = current_collateral
-                        .checked_add(amount)
-                        .ok_or(LendingError::MathOverflow)?;
-                    pool.total_deposits = pool
-                        .total_deposits
-                        .checked_add(amount)
+                    let shares = utils::to_scaled(amount, pool.cumulative_supply_index, false)?;
+                    cpi_utils::mint_shares(
+                        &share_mint_info,
+                        &share_escrow_info,
+                        &ctx.accounts.token_program.to_account_info(),
+                        &[&share_mint_seeds[..]],
+                        shares,
+                    )?;
+                    scaled_collateral = scaled_collateral
+                        .checked_add(shares)
                         .ok_or(LendingError::MathOverflow)?;
+                    pool.add_deposits(amount)?;
                 }
                 Operation::Withdraw { amount } => {
+                    pool.require_action_enabled(PAUSE_WITHDRAW)?;
+                    let collateral = pool.collateral_from_scaled(scaled_collateral)?;
+                    let amount = if amount == AMOUNT_ALL {
+                        obligation.max_withdraw(&pool_key, &pool, collateral, price)?
+                    } else {
+                        amount
+                    };
                     require_gt!(amount, 0, LendingError::InvalidOperation);
-                    let new_collateral = current_collateral
-                        .checked_sub(amount)
-                        .ok_or(LendingError::InsufficientCollateralAmount)?;
                     require!(
-                        utils::is_healthy(
-                            new_collateral,
-                            current_debt,
-                            price,
-                            pool.liquidation_threshold_bps
-                        )?,
-                        LendingError::PositionWouldBecomeUnhealthy
+                        collateral >= amount,
+                        LendingError::InsufficientCollateralAmount
                     );
+                    obligation.apply_withdraw(&pool_key, &pool, amount, price)?;
                     cpi_utils::transfer_from_vault_checked(
                         &ctx.accounts.asset_vault.to_account_info(),
                         &ctx.accounts.user_asset_account.to_account_info(),
@@ -982,7 +2055,21 @@ pub mod lending_protocol {
                         amount,
                         asset_decimals,
                     )?;
-                    current_collateral = new_collateral;
+                    let shares = if amount == collateral {
+                        scaled_collateral
+                    } else {
+                        utils::to_scaled(amount, pool.cumulative_supply_index, true)?
+                    };
+                    cpi_utils::burn_shares(
+                        &share_mint_info,
+                        &share_escrow_info,
+                        &ctx.accounts.token_program.to_account_info(),
+                        &[&share_escrow_seeds[..]],
+                        shares,
+                    )?;
+                    scaled_collateral = scaled_collateral
+                        .checked_sub(shares)
+                        .ok_or(LendingError::InsufficientCollateralAmount)?;
                     pool.total_deposits = pool
                         .total_deposits
                         .checked_sub(amount)
@@ -990,18 +2077,8 @@ pub mod lending_protocol {
                 }
                 Operation::Borrow { amount } => {
                     require_gt!(amount, 0, LendingError::InvalidOperation);
-                    let new_debt = current_debt
-                        .checked_add(amount)
-                        .ok_or(LendingError::MathOverflow)?;
-                    require!(
-                        utils::is_eligible_for_borrow(
-                            current_collateral,
-                            new_debt,
-                            price,
-                            pool.loan_to_value_bps
-                        )?,
-                        LendingError::InsufficientCollateral
-                    );
+                    pool.require_action_enabled(PAUSE_BORROW)?;
+                    obligation.apply_borrow(&pool_key, &pool, amount, price)?;
                     cpi_utils::transfer_from_vault_checked(
                         &ctx.accounts.asset_vault.to_account_info(),
                         &ctx.accounts.user_asset_account.to_account_info(),
@@ -1012,14 +2089,18 @@ pub mod lending_protocol {
                         amount,
                         asset_decimals,
                     )?;
-                    current_debt = new_debt;
-                    pool.total_loans = pool
-                        .total_loans
-                        .checked_add(amount)
+                    let scaled_amount =
+                        utils::to_scaled(amount, pool.cumulative_borrow_index, true)?;
+                    scaled_loan = scaled_loan
+                        .checked_add(scaled_amount)
                         .ok_or(LendingError::MathOverflow)?;
+                    pool.add_loans(amount, scaled_amount)?;
                 }
                 Operation::Repay { amount } => {
                     require_gt!(amount, 0, LendingError::InvalidOperation);
+                    pool.require_action_enabled(PAUSE_REPAY)?;
+                    // `AMOUNT_ALL` (or any amount above the debt) settles it exactly
+                    let current_debt = pool.debt_from_scaled(scaled_loan)?;
                     let actual_repayment = amount.min(current_debt);
                     if actual_repayment > 0 {
                         cpi_utils::transfer_from_user_checked(
@@ -1031,20 +2112,22 @@ pub mod lending_protocol {
                             actual_repayment,
                             asset_decimals,
                         )?;
-                        current_debt = current_debt
-                            .checked_sub(actual_repayment)
-                            .ok_or(LendingError::MathOverflow)?;
-                        pool.total_loans = pool
-                            .total_loans
-                            .checked_sub(actual_repayment)
+                        let scaled_amount = if actual_repayment == current_debt {
+                            scaled_loan
+                        } else {
+                            utils::to_scaled(actual_repayment, pool.cumulative_borrow_index, false)?
+                        };
+                        scaled_loan = scaled_loan
+                            .checked_sub(scaled_amount)
                             .ok_or(LendingError::MathOverflow)?;
+                        pool.remove_loans(actual_repayment, scaled_amount)?;
                     }
                 }
             }
         }
 
-        position.collateral_amount = current_collateral;
-        position.loan_amount = current_debt;
+        position.scaled_collateral = scaled_collateral;
+        position.scaled_loan = scaled_loan;
 
         emit!(OperationsExecuted {
             pool: pool_key,
@@ -1057,8 +2140,9 @@ pub mod lending_protocol {
 // --- 5. Account Context Definitions ---
 
 #[derive(Accounts)]
+#[instruction(market_id: u64)]
 pub struct InitializeMarketConfig<'info> {
-    #[account(init, payer = owner, space = 8 + size_of::<MarketConfig>(), seeds = [MARKET_CONFIG_SEED], bump
+    #[account(init, payer = owner, space = 8 + size_of::<MarketConfig>(), seeds = [MARKET_CONFIG_SEED, owner.key().as_ref(), &market_id.to_le_bytes()], bump
     )]
     pub market_config: AccountLoader<'info, MarketConfig>,
     #[account(mut)]
@@ -1068,44 +2152,138 @@ pub struct InitializeMarketConfig<'info> {
 
 #[derive(Accounts)]
 pub struct UpdateGovernanceAuthority<'info> {
-    #[account(mut, has_one = governance_authority, seeds = [MARKET_CONFIG_SEED], bump)]
+    #[account(mut, has_one = governance_authority)]
     pub market_config: AccountLoader<'info, MarketConfig>,
     pub governance_authority: Signer<'info>,
 }
 
+#[derive(Accounts)]
+pub struct GuardianAction<'info> {
+    #[account(mut)]
+    pub market_config: AccountLoader<'info, MarketConfig>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AcceptGovernanceAuthority<'info> {
+    #[account(mut)]
+    pub market_config: AccountLoader<'info, MarketConfig>,
+    pub pending_governance_authority: Signer<'info>,
+}
+
 #[derive(Accounts)]
 #[instruction(params: AssetPoolParams)]
 pub struct AddAssetPool<'info> {
     #[account(mut, has_one = governance_authority)]
     pub market_config: AccountLoader<'info, MarketConfig>,
-    #[account(init, payer = governance_authority, space = 8 + size_of::<AssetPool>())]
+    #[account(init, payer = governance_authority, space = 8 + size_of::<AssetPool>(), seeds = [ASSET_POOL_SEED, market_config.key().as_ref(), asset_mint.key().as_ref()], bump
+    )]
     pub asset_pool: AccountLoader<'info, AssetPool>,
     #[account(init, payer = governance_authority, seeds = [VAULT_SEED, asset_pool.key().as_ref()], bump, token::mint = asset_mint, token::authority = asset_vault
     )]
     pub asset_vault: InterfaceAccount<'info, TokenAccount>,
     pub asset_mint: InterfaceAccount<'info, Mint>,
+    /// Reused when the mint is listed again after `remove_asset_pool`, since mints cannot be closed.
+    #[account(init_if_needed, payer = governance_authority, seeds = [SHARE_MINT_SEED, asset_pool.key().as_ref()], bump, mint::decimals = asset_mint.decimals, mint::authority = share_mint
+    )]
+    pub share_mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(init, payer = governance_authority, seeds = [SHARE_ESCROW_SEED, asset_pool.key().as_ref()], bump, token::mint = share_mint, token::authority = share_escrow
+    )]
+    pub share_escrow: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(init, payer = governance_authority, seeds = [INSURANCE_VAULT_SEED, asset_pool.key().as_ref()], bump, token::mint = asset_mint, token::authority = insurance_vault
+    )]
+    pub insurance_vault: Box<InterfaceAccount<'info, TokenAccount>>,
     #[account(mut)]
     pub governance_authority: Signer<'info>,
-    /// CHECK: Pyth price feed account, not deserialized.
+    /// CHECK: Primary price feed account, read as `primary_oracle`; not deserialized here.
     pub pyth_price_feed_account: AccountInfo<'info>,
-    /// CHECK: Optional Chainlink price feed account.
+    /// CHECK: Optional fallback price feed account, read as `fallback_oracle`.
     pub chainlink_price_feed_account: Option<AccountInfo<'info>>,
     pub system_program: Program<'info, System>,
     pub token_program: Interface<'info, TokenInterface>,
 }
 
+#[derive(Accounts)]
+pub struct GrowPoolRegistry<'info> {
+    /// CHECK: Resized before it is loaded so markets on an older, shorter layout can migrate;
+    /// the discriminator and governance authority are checked in the handler.
+    #[account(mut, owner = ID)]
+    pub market_config: UncheckedAccount<'info>,
+    #[account(mut)]
+    pub governance_authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
 #[derive(Accounts)]
 pub struct UpdateAssetPool<'info> {
+    #[account(constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
+    pub market_config: AccountLoader<'info, MarketConfig>,
+    #[account(mut)]
+    pub asset_pool: AccountLoader<'info, AssetPool>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SetPoolPauseFlags<'info> {
+    #[account(constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
+    pub market_config: AccountLoader<'info, MarketConfig>,
+    #[account(mut)]
+    pub asset_pool: AccountLoader<'info, AssetPool>,
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct QueueAssetPoolUpdate<'info> {
+    #[account(has_one = governance_authority, constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
+    pub market_config: AccountLoader<'info, MarketConfig>,
+    pub asset_pool: AccountLoader<'info, AssetPool>,
+    #[account(init, payer = governance_authority, space = 8 + PendingParamChange::INIT_SPACE, seeds = [PENDING_PARAM_CHANGE_SEED, asset_pool.key().as_ref()], bump
+    )]
+    pub pending_param_change: Account<'info, PendingParamChange>,
+    #[account(mut)]
+    pub governance_authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct QueueLiquidationConfigUpdate<'info> {
+    #[account(has_one = governance_authority)]
+    pub market_config: AccountLoader<'info, MarketConfig>,
+    #[account(init, payer = governance_authority, space = 8 + PendingLiquidationConfig::INIT_SPACE, seeds = [PENDING_LIQUIDATION_CONFIG_SEED, market_config.key().as_ref()], bump
+    )]
+    pub pending_liquidation_config: Account<'info, PendingLiquidationConfig>,
+    #[account(mut)]
+    pub governance_authority: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ResolveLiquidationConfigUpdate<'info> {
     #[account(mut, has_one = governance_authority)]
     pub market_config: AccountLoader<'info, MarketConfig>,
+    #[account(mut, seeds = [PENDING_LIQUIDATION_CONFIG_SEED, market_config.key().as_ref()], bump, close = governance_authority
+    )]
+    pub pending_liquidation_config: Account<'info, PendingLiquidationConfig>,
+    #[account(mut)]
+    pub governance_authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ResolveAssetPoolUpdate<'info> {
+    #[account(has_one = governance_authority, constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
+    pub market_config: AccountLoader<'info, MarketConfig>,
     #[account(mut)]
     pub asset_pool: AccountLoader<'info, AssetPool>,
+    #[account(mut, seeds = [PENDING_PARAM_CHANGE_SEED, asset_pool.key().as_ref()], bump, close = governance_authority
+    )]
+    pub pending_param_change: Account<'info, PendingParamChange>,
+    #[account(mut)]
     pub governance_authority: Signer<'info>,
 }
 
 #[derive(Accounts)]
 pub struct CollectProtocolFees<'info> {
-    #[account(has_one = governance_authority)]
+    #[account(constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
     pub market_config: AccountLoader<'info, MarketConfig>,
     #[account(mut, constraint = asset_vault.key() == asset_pool.load()?.asset_vault, constraint = asset_mint.key() == asset_pool.load()?.asset_mint
     )]
@@ -1115,6 +2293,65 @@ pub struct CollectProtocolFees<'info> {
     #[account(mut, constraint = destination_account.mint == asset_pool.load()?.asset_mint)]
     pub destination_account: InterfaceAccount<'info, TokenAccount>,
     pub asset_mint: InterfaceAccount<'info, Mint>,
+    pub authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct FundInsuranceReserve<'info> {
+    #[account(mut, constraint = asset_vault.key() == asset_pool.load()?.asset_vault, constraint = asset_mint.key() == asset_pool.load()?.asset_mint
+    )]
+    pub asset_pool: AccountLoader<'info, AssetPool>,
+    #[account(mut, seeds = [VAULT_SEED, asset_pool.key().as_ref()], bump)]
+    pub asset_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut, seeds = [INSURANCE_VAULT_SEED, asset_pool.key().as_ref()], bump)]
+    pub insurance_vault: InterfaceAccount<'info, TokenAccount>,
+    pub asset_mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct WithdrawInsuranceReserve<'info> {
+    #[account(has_one = governance_authority, constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
+    pub market_config: AccountLoader<'info, MarketConfig>,
+    #[account(mut, constraint = asset_mint.key() == asset_pool.load()?.asset_mint)]
+    pub asset_pool: AccountLoader<'info, AssetPool>,
+    #[account(mut, seeds = [INSURANCE_VAULT_SEED, asset_pool.key().as_ref()], bump)]
+    pub insurance_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut, constraint = destination_account.mint == asset_pool.load()?.asset_mint)]
+    pub destination_account: InterfaceAccount<'info, TokenAccount>,
+    pub asset_mint: InterfaceAccount<'info, Mint>,
+    pub governance_authority: Signer<'info>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct DelistAssetPool<'info> {
+    #[account(has_one = governance_authority, constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
+    pub market_config: AccountLoader<'info, MarketConfig>,
+    #[account(mut)]
+    pub asset_pool: AccountLoader<'info, AssetPool>,
+    pub governance_authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct RemoveAssetPool<'info> {
+    #[account(mut, has_one = governance_authority, constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
+    pub market_config: AccountLoader<'info, MarketConfig>,
+    #[account(mut, close = governance_authority, constraint = asset_mint.key() == asset_pool.load()?.asset_mint)]
+    pub asset_pool: AccountLoader<'info, AssetPool>,
+    #[account(mut, seeds = [VAULT_SEED, asset_pool.key().as_ref()], bump)]
+    pub asset_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut, seeds = [INSURANCE_VAULT_SEED, asset_pool.key().as_ref()], bump)]
+    pub insurance_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(mut, seeds = [SHARE_ESCROW_SEED, asset_pool.key().as_ref()], bump)]
+    pub share_escrow: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(seeds = [SHARE_MINT_SEED, asset_pool.key().as_ref()], bump)]
+    pub share_mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(mut, constraint = destination_account.mint == asset_pool.load()?.asset_mint)]
+    pub destination_account: InterfaceAccount<'info, TokenAccount>,
+    pub asset_mint: InterfaceAccount<'info, Mint>,
+    #[account(mut)]
     pub governance_authority: Signer<'info>,
     pub token_program: Interface<'info, TokenInterface>,
 }
@@ -1130,13 +2367,64 @@ pub struct CreateUserPosition<'info> {
     pub system_program: Program<'info, System>,
 }
 
+#[derive(Accounts)]
+pub struct CloseUserPosition<'info> {
+    #[account(mut, seeds = [USER_POSITION_SEED, asset_pool.key().as_ref(), user.key().as_ref()], bump, close = user)]
+    pub user_position: AccountLoader<'info, UserPosition>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    /// CHECK: Only used as a seed; the pool may already have been removed from the market.
+    pub asset_pool: UncheckedAccount<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CreateObligation<'info> {
+    pub market_config: AccountLoader<'info, MarketConfig>,
+    #[account(init, payer = user, space = 8 + size_of::<Obligation>(), seeds = [OBLIGATION_SEED, market_config.key().as_ref(), user.key().as_ref()], bump
+    )]
+    pub obligation: AccountLoader<'info, Obligation>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct OpenObligationLeg<'info> {
+    #[account(constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
+    pub market_config: AccountLoader<'info, MarketConfig>,
+    #[account(mut, seeds = [OBLIGATION_SEED, market_config.key().as_ref(), user.key().as_ref()], bump)]
+    pub obligation: AccountLoader<'info, Obligation>,
+    pub asset_pool: AccountLoader<'info, AssetPool>,
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CloseObligationLeg<'info> {
+    pub market_config: AccountLoader<'info, MarketConfig>,
+    #[account(mut, seeds = [OBLIGATION_SEED, market_config.key().as_ref(), user.key().as_ref()], bump)]
+    pub obligation: AccountLoader<'info, Obligation>,
+    /// CHECK: Only used as a key; the leg must already be open.
+    pub asset_pool: UncheckedAccount<'info>,
+    /// CHECK: The user's position PDA for the pool; may not exist yet.
+    #[account(seeds = [USER_POSITION_SEED, asset_pool.key().as_ref(), user.key().as_ref()], bump)]
+    pub user_position: UncheckedAccount<'info>,
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct RefreshObligation<'info> {
+    #[account(mut)]
+    pub obligation: AccountLoader<'info, Obligation>,
+}
+
 #[derive(Accounts)]
 pub struct Deposit<'info> {
-    #[account(seeds = [MARKET_CONFIG_SEED], bump)]
+    #[account(constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
     pub market_config: AccountLoader<'info, MarketConfig>,
     #[account(mut, constraint = asset_mint.key() == asset_pool.load()?.asset_mint)]
     pub asset_pool: AccountLoader<'info, AssetPool>,
-    #[account(mut, seeds = [USER_POSITION_SEED, asset_pool.key().as_ref(), user.key().as_ref()], bump, constraint = user_position.load()?.owner == user.key() @ LendingError::InvalidOwner
+    /// Created on the user's first deposit into the pool; see `UserPosition::init_if_new`.
+    #[account(init_if_needed, payer = user, space = 8 + size_of::<UserPosition>(), seeds = [USER_POSITION_SEED, asset_pool.key().as_ref(), user.key().as_ref()], bump
     )]
     pub user_position: AccountLoader<'info, UserPosition>,
     #[account(mut)]
@@ -1146,18 +2434,25 @@ pub struct Deposit<'info> {
     #[account(mut, constraint = asset_vault.key() == asset_pool.load()?.asset_vault)]
     pub asset_vault: InterfaceAccount<'info, TokenAccount>,
     pub asset_mint: InterfaceAccount<'info, Mint>,
+    #[account(mut, seeds = [SHARE_MINT_SEED, asset_pool.key().as_ref()], bump)]
+    pub share_mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(mut, seeds = [SHARE_ESCROW_SEED, asset_pool.key().as_ref()], bump)]
+    pub share_escrow: Box<InterfaceAccount<'info, TokenAccount>>,
     pub token_program: Interface<'info, TokenInterface>,
+    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
 pub struct Withdraw<'info> {
-    #[account(seeds = [MARKET_CONFIG_SEED], bump)]
+    #[account(constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
     pub market_config: AccountLoader<'info, MarketConfig>,
     #[account(mut)] //, constraint = asset_mint.key() == asset_pool.load()?.asset_mint
     pub asset_pool: AccountLoader<'info, AssetPool>,
     #[account(mut, seeds = [USER_POSITION_SEED, asset_pool.key().as_ref(), user.key().as_ref()], bump,
     )] // constraint = user_position.load()?.owner == user.key() @ LendingError::InvalidOwner
     pub user_position: AccountLoader<'info, UserPosition>,
+    #[account(mut, seeds = [OBLIGATION_SEED, market_config.key().as_ref(), user.key().as_ref()], bump)]
+    pub obligation: AccountLoader<'info, Obligation>,
     pub user: Signer<'info>,
     #[account(mut)] // constraint = user_asset_account.mint == asset_pool.load()?.asset_mint
     pub user_asset_account: InterfaceAccount<'info, TokenAccount>,
@@ -1165,6 +2460,10 @@ pub struct Withdraw<'info> {
     )] //constraint = asset_vault.key() == asset_pool.load()?.asset_vault,
     pub asset_vault: InterfaceAccount<'info, TokenAccount>,
     pub asset_mint: InterfaceAccount<'info, Mint>,
+    #[account(mut, seeds = [SHARE_MINT_SEED, asset_pool.key().as_ref()], bump)]
+    pub share_mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(mut, seeds = [SHARE_ESCROW_SEED, asset_pool.key().as_ref()], bump)]
+    pub share_escrow: Box<InterfaceAccount<'info, TokenAccount>>,
     pub token_program: Interface<'info, TokenInterface>,
     /// CHECK: Pyth price feed, address validated against asset_pool.
     #[account()]
@@ -1176,15 +2475,60 @@ pub struct Withdraw<'info> {
     pub chainlink_price_feed_account: AccountInfo<'info>,
 }
 
+#[derive(Accounts)]
+pub struct LockCollateral<'info> {
+    #[account(constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
+    pub market_config: AccountLoader<'info, MarketConfig>,
+    pub asset_pool: AccountLoader<'info, AssetPool>,
+    #[account(mut, seeds = [USER_POSITION_SEED, asset_pool.key().as_ref(), user.key().as_ref()], bump, constraint = user_position.load()?.owner == user.key() @ LendingError::InvalidOwner
+    )]
+    pub user_position: AccountLoader<'info, UserPosition>,
+    pub user: Signer<'info>,
+    #[account(mut, constraint = user_share_account.mint == share_mint.key())]
+    pub user_share_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(seeds = [SHARE_MINT_SEED, asset_pool.key().as_ref()], bump)]
+    pub share_mint: InterfaceAccount<'info, Mint>,
+    #[account(mut, seeds = [SHARE_ESCROW_SEED, asset_pool.key().as_ref()], bump)]
+    pub share_escrow: InterfaceAccount<'info, TokenAccount>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
+#[derive(Accounts)]
+pub struct UnlockCollateral<'info> {
+    #[account(constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
+    pub market_config: AccountLoader<'info, MarketConfig>,
+    #[account(mut)]
+    pub asset_pool: AccountLoader<'info, AssetPool>,
+    #[account(mut, seeds = [USER_POSITION_SEED, asset_pool.key().as_ref(), user.key().as_ref()], bump, constraint = user_position.load()?.owner == user.key() @ LendingError::InvalidOwner
+    )]
+    pub user_position: AccountLoader<'info, UserPosition>,
+    #[account(mut, seeds = [OBLIGATION_SEED, market_config.key().as_ref(), user.key().as_ref()], bump)]
+    pub obligation: AccountLoader<'info, Obligation>,
+    pub user: Signer<'info>,
+    #[account(mut, constraint = user_share_account.mint == share_mint.key())]
+    pub user_share_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(seeds = [SHARE_MINT_SEED, asset_pool.key().as_ref()], bump)]
+    pub share_mint: InterfaceAccount<'info, Mint>,
+    #[account(mut, seeds = [SHARE_ESCROW_SEED, asset_pool.key().as_ref()], bump)]
+    pub share_escrow: InterfaceAccount<'info, TokenAccount>,
+    pub token_program: Interface<'info, TokenInterface>,
+    /// CHECK: Pyth price feed, address validated against asset_pool.
+    pub pyth_price_feed_account: AccountInfo<'info>,
+    /// CHECK: Optional Chainlink price feed, address validated against asset_pool.
+    pub chainlink_price_feed_account: AccountInfo<'info>,
+}
+
 #[derive(Accounts)]
 pub struct Borrow<'info> {
-    #[account(seeds = [MARKET_CONFIG_SEED], bump)]
+    #[account(constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
     pub market_config: AccountLoader<'info, MarketConfig>,
     #[account(mut)] //constraint = asset_mint.key() == asset_pool.load()?.asset_mint
     pub asset_pool: AccountLoader<'info, AssetPool>,
     #[account(mut, seeds = [USER_POSITION_SEED, asset_pool.key().as_ref(), user.key().as_ref()], bump
     )] //constraint = user_position.load()?.owner == user.key() @ LendingError::InvalidOwner
     pub user_position: AccountLoader<'info, UserPosition>,
+    #[account(mut, seeds = [OBLIGATION_SEED, market_config.key().as_ref(), user.key().as_ref()], bump)]
+    pub obligation: AccountLoader<'info, Obligation>,
     pub user: Signer<'info>,
     #[account(mut)] //constraint = user_asset_account.mint == asset_pool.load()?.asset_mint
     pub user_asset_account: InterfaceAccount<'info, TokenAccount>,
@@ -1205,9 +2549,9 @@ pub struct Borrow<'info> {
 
 #[derive(Accounts)]
 pub struct Repay<'info> {
-    #[account(seeds = [MARKET_CONFIG_SEED], bump)]
+    #[account(constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
     pub market_config: AccountLoader<'info, MarketConfig>,
-    #[account(mut)] //constraint = asset_mint.key() == asset_pool.load()?.asset_mint
+    #[account(mut, constraint = asset_mint.key() == asset_pool.load()?.asset_mint)]
     pub asset_pool: AccountLoader<'info, AssetPool>,
     #[account(mut,  seeds = [USER_POSITION_SEED, asset_pool.key().as_ref(), user.key().as_ref()], bump
     )] //constraint = user_position.load()?.owner ==  user.key() @ LendingError::InvalidOwner
@@ -1215,7 +2559,8 @@ pub struct Repay<'info> {
     pub user: Signer<'info>,
     #[account(mut)] //constraint = user_asset_account.mint == asset_pool.load()?.asset_mint
     pub user_asset_account: InterfaceAccount<'info, TokenAccount>,
-    #[account(mut)] //constraint = asset_vault.key() == asset_pool.load()?.asset_vault
+    #[account(mut, constraint = asset_vault.key() == asset_pool.load()?.asset_vault, seeds = [VAULT_SEED, asset_pool.key().as_ref()], bump
+    )]
     pub asset_vault: InterfaceAccount<'info, TokenAccount>,
     pub asset_mint: InterfaceAccount<'info, Mint>,
     pub token_program: Interface<'info, TokenInterface>,
@@ -1223,7 +2568,10 @@ pub struct Repay<'info> {
 
 #[derive(Accounts)]
 pub struct Liquidate<'info> {
-    #[account(seeds = [MARKET_CONFIG_SEED], bump)]
+    #[account(
+        constraint = collateral_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch,
+        constraint = loan_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch
+    )]
     pub market_config: AccountLoader<'info, MarketConfig>,
     #[account(mut)]
     pub collateral_pool: AccountLoader<'info, AssetPool>,
@@ -1241,6 +2589,8 @@ pub struct Liquidate<'info> {
     #[account(mut, seeds = [USER_POSITION_SEED, loan_pool.key().as_ref(), borrower.key().as_ref()], bump,
     )] //constraint = borrower_loan_position.load()?.owner == borrower.key()
     pub borrower_loan_position: AccountLoader<'info, UserPosition>,
+    #[account(mut, seeds = [OBLIGATION_SEED, market_config.key().as_ref(), borrower.key().as_ref()], bump)]
+    pub obligation: AccountLoader<'info, Obligation>,
     /// CHECK: Borrower's main account, ownership checked on position accounts.
     pub borrower: AccountInfo<'info>,
     pub liquidator: Signer<'info>,
@@ -1254,6 +2604,10 @@ pub struct Liquidate<'info> {
     pub collateral_vault: InterfaceAccount<'info, TokenAccount>,
     #[account(mut)] //constraint = loan_vault.key() == loan_pool.load()?.asset_vault
     pub loan_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut, seeds = [SHARE_MINT_SEED, collateral_pool.key().as_ref()], bump)]
+    pub collateral_share_mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(mut, seeds = [SHARE_ESCROW_SEED, collateral_pool.key().as_ref()], bump)]
+    pub collateral_share_escrow: Box<InterfaceAccount<'info, TokenAccount>>,
     pub token_program: Interface<'info, TokenInterface>,
     /// CHECK: Price feed address validated against collateral_pool account.
     #[account()]
@@ -1273,9 +2627,30 @@ pub struct Liquidate<'info> {
     pub loan_chainlink_feed_account: AccountInfo<'info>,
 }
 
+#[derive(Accounts)]
+pub struct SocializeBadDebt<'info> {
+    #[account(constraint = loan_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
+    pub market_config: AccountLoader<'info, MarketConfig>,
+    #[account(mut)]
+    pub loan_pool: AccountLoader<'info, AssetPool>,
+    #[account(mut, seeds = [USER_POSITION_SEED, loan_pool.key().as_ref(), borrower.key().as_ref()], bump)]
+    pub borrower_loan_position: AccountLoader<'info, UserPosition>,
+    #[account(mut, seeds = [OBLIGATION_SEED, market_config.key().as_ref(), borrower.key().as_ref()], bump)]
+    pub obligation: AccountLoader<'info, Obligation>,
+    /// CHECK: Borrower's main account, used for PDA derivation.
+    pub borrower: AccountInfo<'info>,
+    #[account(mut, seeds = [VAULT_SEED, loan_pool.key().as_ref()], bump)]
+    pub loan_vault: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut, seeds = [INSURANCE_VAULT_SEED, loan_pool.key().as_ref()], bump)]
+    pub insurance_vault: Box<InterfaceAccount<'info, TokenAccount>>,
+    #[account(constraint = loan_mint.key() == loan_pool.load()?.asset_mint @ LendingError::LoanMintMismatch)]
+    pub loan_mint: InterfaceAccount<'info, Mint>,
+    pub token_program: Interface<'info, TokenInterface>,
+}
+
 #[derive(Accounts)]
 pub struct FlashLoan<'info> {
-    #[account(seeds = [MARKET_CONFIG_SEED], bump)]
+    #[account(constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
     pub market_config: AccountLoader<'info, MarketConfig>,
     #[account(mut, constraint = asset_mint.key() == asset_pool.load()?.asset_mint)]
     pub asset_pool: AccountLoader<'info, AssetPool>,
@@ -1325,7 +2700,7 @@ pub struct RevokeDelegationAccounts<'info> {
 
 #[derive(Accounts)]
 pub struct BorrowDelegated<'info> {
-    #[account(seeds = [MARKET_CONFIG_SEED], bump)]
+    #[account(constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
     pub market_config: AccountLoader<'info, MarketConfig>,
     #[account(mut)] //constraint = asset_mint.key() == asset_pool.load()?.asset_mint
     pub asset_pool: AccountLoader<'info, AssetPool>,
@@ -1335,6 +2710,8 @@ pub struct BorrowDelegated<'info> {
     pub asset_mint: InterfaceAccount<'info, Mint>,
     #[account(mut)] //has_one = owner,constraint = owner_position.load()?.pool == asset_pool.key()
     pub owner_position: AccountLoader<'info, UserPosition>,
+    #[account(mut, seeds = [OBLIGATION_SEED, market_config.key().as_ref(), owner.key().as_ref()], bump)]
+    pub obligation: AccountLoader<'info, Obligation>,
     /// CHECK: Position owner, not a signer.
     pub owner: AccountInfo<'info>,
     #[account(mut, seeds = [CREDIT_DELEGATION_SEED, owner.key().as_ref(), asset_pool.key().as_ref(), delegatee.key().as_ref()], bump,
@@ -1357,7 +2734,7 @@ pub struct BorrowDelegated<'info> {
 
 #[derive(Accounts)]
 pub struct ExecuteOperations<'info> {
-    #[account(seeds = [MARKET_CONFIG_SEED], bump)]
+    #[account(constraint = asset_pool.load()?.market == market_config.key() @ LendingError::MarketMismatch)]
     pub market_config: AccountLoader<'info, MarketConfig>,
     #[account(mut, constraint = asset_mint.key() == asset_pool.load()?.asset_mint)]
     pub asset_pool: AccountLoader<'info, AssetPool>,
@@ -1365,13 +2742,21 @@ pub struct ExecuteOperations<'info> {
     )]
     pub asset_vault: InterfaceAccount<'info, TokenAccount>,
     pub asset_mint: InterfaceAccount<'info, Mint>,
-    #[account(mut, constraint = user_position.load()?.owner == user.key() @ LendingError::InvalidOwner, seeds = [USER_POSITION_SEED, asset_pool.key().as_ref(), user.key().as_ref()], bump
+    /// Created on first use; see `UserPosition::init_if_new`.
+    #[account(init_if_needed, payer = user, space = 8 + size_of::<UserPosition>(), seeds = [USER_POSITION_SEED, asset_pool.key().as_ref(), user.key().as_ref()], bump
     )]
     pub user_position: AccountLoader<'info, UserPosition>,
+    #[account(mut, seeds = [OBLIGATION_SEED, market_config.key().as_ref(), user.key().as_ref()], bump)]
+    pub obligation: AccountLoader<'info, Obligation>,
+    #[account(mut)]
     pub user: Signer<'info>,
     #[account(mut, constraint = user_asset_account.owner == user.key() && user_asset_account.mint == asset_pool.load()?.asset_mint
     )]
     pub user_asset_account: InterfaceAccount<'info, TokenAccount>,
+    #[account(mut, seeds = [SHARE_MINT_SEED, asset_pool.key().as_ref()], bump)]
+    pub share_mint: Box<InterfaceAccount<'info, Mint>>,
+    #[account(mut, seeds = [SHARE_ESCROW_SEED, asset_pool.key().as_ref()], bump)]
+    pub share_escrow: Box<InterfaceAccount<'info, TokenAccount>>,
     pub token_program: Interface<'info, TokenInterface>,
     /// CHECK: Price feed address validated against asset_pool.
     #[account()]
@@ -1381,12 +2766,15 @@ pub struct ExecuteOperations<'info> {
     #[account(constraint = chainlink_price_feed_account.key() == asset_pool.load()?.chainlink_price_feed @ LendingError::InvalidOracleAccount
     )]
     pub chainlink_price_feed_account: AccountInfo<'info>,
+    pub system_program: Program<'info, System>,
 }
 
 // --- 6. Events ---
 
 #[event]
 pub struct MarketConfigInitialized {
+    pub market: Pubkey,
+    pub market_id: u64,
     pub new_governance_authority: Pubkey,
 }
 #[event]
@@ -1395,19 +2783,82 @@ pub struct GovernanceAuthorityChanged {
     pub new_authority: Pubkey,
 }
 #[event]
+pub struct MarketRoleUpdated {
+    pub role: MarketRole,
+    pub old_authority: Pubkey,
+    pub new_authority: Pubkey,
+}
+#[event]
+pub struct GovernanceAuthorityProposed {
+    pub current_authority: Pubkey,
+    pub proposed_authority: Pubkey,
+}
+#[event]
+pub struct GovernanceAuthorityTransferCancelled {
+    pub cancelled_authority: Pubkey,
+}
+#[event]
 pub struct ProtocolPaused {}
 #[event]
 pub struct ProtocolUnpaused {}
 #[event]
 pub struct ProtocolWithdrawOnlyModeEnabled {}
 #[event]
+pub struct ParamChangeDelayUpdated {
+    pub old_delay_seconds: i64,
+    pub new_delay_seconds: i64,
+}
+#[event]
+pub struct ParamChangeQueued {
+    pub pool: Pubkey,
+    pub eta: i64,
+}
+#[event]
+pub struct ParamChangeCancelled {
+    pub pool: Pubkey,
+}
+#[event]
+pub struct LiquidationConfigChangeQueued {
+    pub market: Pubkey,
+    pub eta: i64,
+}
+#[event]
+pub struct LiquidationConfigChangeCancelled {
+    pub market: Pubkey,
+}
+#[event]
+pub struct LiquidationConfigUpdated {
+    pub dust_value: u128,
+    pub full_liquidation_health_factor_bps: u64,
+}
+#[event]
 pub struct AssetPoolAdded {
     pub pool_key: Pubkey,
     pub asset_mint: Pubkey,
 }
 #[event]
+pub struct PoolRegistryGrown {
+    pub market: Pubkey,
+    pub capacity: u16,
+}
+#[event]
 pub struct AssetPoolUpdated {
     pub pool_key: Pubkey,
+    pub liquidation_bonus_bps: u64,
+    pub close_factor_bps: u64,
+    pub insurance_fee_bps: u64,
+    pub supply_cap: u64,
+    pub borrow_cap: u64,
+    pub max_price_age_seconds: u64,
+    pub max_confidence_bps: u64,
+    pub max_oracle_deviation_bps: u64,
+    pub conservative_pricing: bool,
+}
+#[event]
+pub struct PoolPauseFlagsChanged {
+    pub pool: Pubkey,
+    pub old_paused_actions: u8,
+    pub new_paused_actions: u8,
 }
 #[event]
 pub struct ProtocolFeesCollected {
@@ -1416,6 +2867,27 @@ pub struct ProtocolFeesCollected {
     pub amount: u64,
 }
 #[event]
+pub struct InsuranceReserveFunded {
+    pub pool: Pubkey,
+    pub amount: u64,
+}
+#[event]
+pub struct InsuranceReserveWithdrawn {
+    pub pool: Pubkey,
+    pub recipient: Pubkey,
+    pub amount: u64,
+}
+#[event]
+pub struct AssetPoolDelisted {
+    pub pool: Pubkey,
+}
+#[event]
+pub struct AssetPoolRemoved {
+    pub pool: Pubkey,
+    pub asset_mint: Pubkey,
+    pub swept_amount: u64,
+}
+#[event]
 pub struct Deposited {
     pub pool: Pubkey,
     pub user: Pubkey,
@@ -1428,6 +2900,18 @@ pub struct Withdrawn {
     pub amount: u64,
 }
 #[event]
+pub struct CollateralLocked {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub shares: u64,
+}
+#[event]
+pub struct CollateralUnlocked {
+    pub pool: Pubkey,
+    pub user: Pubkey,
+    pub shares: u64,
+}
+#[event]
 pub struct Borrowed {
     pub pool: Pubkey,
     pub user: Pubkey,
@@ -1449,6 +2933,22 @@ pub struct Liquidation {
     pub seized_collateral_amount: u64,
 }
 #[event]
+pub struct BadDebtCollateralSeized {
+    pub pool: Pubkey,
+    pub borrower: Pubkey,
+    pub amount: u64,
+}
+#[event]
+pub struct BadDebtSocialized {
+    pub pool: Pubkey,
+    pub borrower: Pubkey,
+    pub bad_debt: u64,
+    pub covered_by_reserve: u64,
+    pub covered_by_fees: u64,
+    pub socialized: u64,
+    pub supply_index: u128,
+}
+#[event]
 pub struct FlashLoaned {
     pub pool: Pubkey,
     pub receiver: Pubkey,
@@ -1475,6 +2975,14 @@ pub struct OperationsExecuted {
     pub user: Pubkey,
 }
 
+#[event]
+pub struct ObligationLegChanged {
+    pub owner: Pubkey,
+    pub pool: Pubkey,
+    pub side: ObligationLegSide,
+    pub open: bool,
+}
+
 // --- 7. State Account Definitions ---
 
 #[account(zero_copy)]
@@ -1485,21 +2993,137 @@ pub struct MarketConfig {
     /// Protocol status: 0=Active, 1=Paused, 2=WithdrawOnly
     pub status: u8,
     pub pool_count: u16,
-    pub pools: [Pubkey; MarketConfig::MAX_POOLS],
+    /// First `INLINE_POOLS` registry slots; `grow_pool_registry` appends more after the struct.
+    pub pools: [Pubkey; MarketConfig::INLINE_POOLS],
+    /// Obligations borrowing less than this value can be liquidated in full; 0 disables.
+    pub liquidation_dust_value: u128,
+    /// Obligations below this health factor can be liquidated in full; 0 disables.
+    pub full_liquidation_health_factor_bps: u64,
+    /// Minimum seconds between queuing and executing a pool parameter change.
+    pub param_change_delay: i64,
+    /// Authority proposed to take over governance; the default key when none is pending.
+    pub pending_governance_authority: Pubkey,
+    /// Can pause the market or individual pool actions, but not resume them.
+    pub guardian: Pubkey,
+    /// Can tune pool parameters within `RISK_ADMIN_MAX_STEP_BPS`, but not fees.
+    pub risk_admin: Pubkey,
+    /// Can collect protocol fees, but only into accounts owned by `fee_treasury`.
+    pub fee_collector: Pubkey,
+    /// Owner of the token accounts the fee collector pays out to.
+    pub fee_treasury: Pubkey,
+    /// Key that created the market; with `market_id` it seeds the market's address.
+    pub creator: Pubkey,
+    pub market_id: u64,
 }
 
 impl MarketConfig {
-    pub const MAX_POOLS: usize = 32;
+    pub const INLINE_POOLS: usize = 32;
+    /// Account size without overflow registry slots.
+    pub const LEN: usize = 8 + size_of::<MarketConfig>();
+
+    /// Borrows the market together with its overflow registry slots, the `Pubkey`s stored
+    /// after the struct. The account's owner and discriminator must already be checked.
+    pub fn load_registry<'a>(info: &'a AccountInfo) -> Result<(Ref<'a, Self>, Ref<'a, [Pubkey]>)> {
+        let data = info.try_borrow_data()?;
+        require!(
+            data.len() >= Self::LEN && (data.len() - Self::LEN) % size_of::<Pubkey>() == 0,
+            ErrorCode::AccountDidNotDeserialize
+        );
+        Ok(Ref::map_split(data, |data| {
+            let (config, overflow) = data.split_at(Self::LEN);
+            (
+                bytemuck::from_bytes(&config[8..]),
+                bytemuck::cast_slice(overflow),
+            )
+        }))
+    }
+
+    /// Mutable counterpart of `load_registry`.
+    pub fn load_registry_mut<'a>(
+        info: &'a AccountInfo,
+    ) -> Result<(RefMut<'a, Self>, RefMut<'a, [Pubkey]>)> {
+        let data = info.try_borrow_mut_data()?;
+        require!(
+            data.len() >= Self::LEN && (data.len() - Self::LEN) % size_of::<Pubkey>() == 0,
+            ErrorCode::AccountDidNotDeserialize
+        );
+        Ok(RefMut::map_split(data, |data| {
+            let (config, overflow) = data.split_at_mut(Self::LEN);
+            (
+                bytemuck::from_bytes_mut(&mut config[8..]),
+                bytemuck::cast_slice_mut(overflow),
+            )
+        }))
+    }
+
+    fn pool_at(&self, overflow: &[Pubkey], index: usize) -> Pubkey {
+        match index.checked_sub(Self::INLINE_POOLS) {
+            None => self.pools[index],
+            Some(index) => overflow[index],
+        }
+    }
+
+    fn set_pool_at(&mut self, overflow: &mut [Pubkey], index: usize, pool_key: Pubkey) {
+        match index.checked_sub(Self::INLINE_POOLS) {
+            None => self.pools[index] = pool_key,
+            Some(index) => overflow[index] = pool_key,
+        }
+    }
 
-    pub fn add_pool(&mut self, pool_key: Pubkey) -> Result<()> {
+    pub fn is_listed(&self, overflow: &[Pubkey], pool_key: &Pubkey) -> bool {
+        (0..self.pool_count as usize).any(|index| self.pool_at(overflow, index) == *pool_key)
+    }
+
+    pub fn add_pool(&mut self, overflow: &mut [Pubkey], pool_key: Pubkey) -> Result<()> {
+        require!(
+            !self.is_listed(overflow, &pool_key),
+            LendingError::AssetPoolAlreadyListed
+        );
+        let count = self.pool_count as usize;
         require!(
-            self.pool_count < (Self::MAX_POOLS as u16),
+            count < Self::INLINE_POOLS + overflow.len() && count < u16::MAX as usize,
             LendingError::MaxAssetsExceeded
         );
-        self.pools[self.pool_count as usize] = pool_key;
+        self.set_pool_at(overflow, count, pool_key);
         self.pool_count += 1;
         Ok(())
     }
+
+    /// Drops `pool_key` from the registry, shifting later pools down to keep it contiguous.
+    pub fn remove_pool(&mut self, overflow: &mut [Pubkey], pool_key: Pubkey) -> Result<()> {
+        let count = self.pool_count as usize;
+        let index = (0..count)
+            .find(|&index| self.pool_at(overflow, index) == pool_key)
+            .ok_or(LendingError::InvalidAssetPool)?;
+        for index in index..count - 1 {
+            let next = self.pool_at(overflow, index + 1);
+            self.set_pool_at(overflow, index, next);
+        }
+        self.set_pool_at(overflow, count - 1, Pubkey::default());
+        self.pool_count -= 1;
+        Ok(())
+    }
+
+    /// Returns true if `signer` is the governance authority or holds the assigned `role`.
+    pub fn is_governance_or(&self, role: Pubkey, signer: &Pubkey) -> bool {
+        *signer == self.governance_authority || (role != Pubkey::default() && *signer == role)
+    }
+
+    /// Validates and applies a liquidation config; see `update_liquidation_config`.
+    pub fn set_liquidation_config(
+        &mut self,
+        dust_value: u128,
+        full_liquidation_health_factor_bps: u64,
+    ) -> Result<()> {
+        // Liquidatable obligations are always below a health factor of 100%
+        require!(
+            full_liquidation_health_factor_bps < BASIS_POINTS_DIVISOR as u64,
+            LendingError::InvalidHealthFactor
+        );
+        self.liquidation_dust_value = dust_value;
+        self.full_liquidation_health_factor_bps = full_liquidation_health_factor_bps;
+        Ok(())
+    }
 }
 
 #[account(zero_copy)]
@@ -1522,6 +3146,53 @@ pub struct AssetPool {
     pub kink_slope_bps: u128,
     pub protocol_fee_bps: u64,
     pub flash_loan_fee_bps: u64,
+    /// Cumulative borrow interest index, scaled by `INDEX_PRECISION`.
+    pub cumulative_borrow_index: u128,
+    /// Cumulative supplier interest index, scaled by `INDEX_PRECISION`.
+    pub cumulative_supply_index: u128,
+    /// Decimals of `asset_mint`, cached so positions can be valued without the mint account.
+    pub asset_decimals: u8,
+    /// Mint of the pool's deposit shares; one share is one unit of scaled collateral.
+    pub share_mint: Pubkey,
+    /// Extra collateral value paid to liquidators when this pool's asset is seized.
+    pub liquidation_bonus_bps: u64,
+    /// Largest share of a debt in this pool that one liquidation may repay.
+    pub close_factor_bps: u64,
+    /// Token account holding the pool's insurance reserve.
+    pub insurance_vault: Pubkey,
+    /// Share of the interest set aside for the insurance reserve.
+    pub insurance_fee_bps: u64,
+    /// Insurance share of the interest still held in `asset_vault`.
+    pub accrued_insurance_fees: u64,
+    /// Largest `total_deposits` that new deposits may bring the pool to; zero means no cap.
+    pub supply_cap: u64,
+    /// Largest `total_loans` that new borrows may bring the pool to; zero means no cap.
+    pub borrow_cap: u64,
+    /// Bitmask of actions governance has paused on this pool (`PAUSE_*`).
+    pub paused_actions: u8,
+    /// Market the pool belongs to.
+    pub market: Pubkey,
+    /// Listing status: 0=Active, 1=ReduceOnly (being delisted)
+    pub status: u8,
+    /// Oldest oracle price, in seconds, the pool accepts.
+    pub max_price_age_seconds: u64,
+    /// Widest Pyth confidence interval or Switchboard standard deviation the pool accepts,
+    /// in bps of the price.
+    pub max_confidence_bps: u64,
+    /// Provider behind `pyth_price_feed`, the primary feed (`OracleSource` as u8).
+    pub primary_oracle: u8,
+    /// Provider behind `chainlink_price_feed`, the fallback feed (`OracleSource` as u8).
+    pub fallback_oracle: u8,
+    /// Pyth price feed ID that `PythPull` price updates must carry.
+    pub pyth_feed_id: [u8; 32],
+    /// Largest gap between the primary and fallback prices, in bps of the lower one. When
+    /// non-zero, both feeds must price the asset; zero trusts the primary feed alone.
+    pub max_oracle_deviation_bps: u64,
+    /// 1 if health checks value collateral at `price - conf` and debt at `price + conf`.
+    pub conservative_pricing: u8,
+    /// Sum of every position's `scaled_loan`. Unlike `total_loans`, which can reach zero while
+    /// rounded-up debts are still open, this is zero only once no position owes anything.
+    pub total_scaled_loans: u64,
 }
 
 #[account(zero_copy)]
@@ -1530,8 +3201,29 @@ pub struct AssetPool {
 pub struct UserPosition {
     pub owner: Pubkey,
     pub pool: Pubkey,
-    pub collateral_amount: u64,
-    pub loan_amount: u64,
+    /// Collateral balance divided by the pool's supply index at deposit time.
+    pub scaled_collateral: u64,
+    /// Debt balance divided by the pool's borrow index at borrow time.
+    pub scaled_loan: u64,
+}
+
+/// Pool parameters queued by governance, applicable from `eta`.
+#[account]
+#[derive(InitSpace)]
+pub struct PendingParamChange {
+    pub asset_pool: Pubkey,
+    pub params: AssetPoolParams,
+    pub eta: i64,
+}
+
+/// Liquidation config queued by governance, applicable from `eta`.
+#[account]
+#[derive(InitSpace)]
+pub struct PendingLiquidationConfig {
+    pub market: Pubkey,
+    pub dust_value: u128,
+    pub full_liquidation_health_factor_bps: u64,
+    pub eta: i64,
 }
 
 #[account(zero_copy)]
@@ -1545,9 +3237,34 @@ pub struct CreditDelegation {
     pub delegated_amount: u64,
 }
 
+#[account(zero_copy)]
+#[repr(C, packed)]
+#[derive(Default)]
+pub struct Obligation {
+    pub owner: Pubkey,
+    /// Slot of the last `refresh_obligation`; the cached values are only trusted within it.
+    pub last_update_slot: u64,
+    /// Market value of the collateral in all deposit legs.
+    pub deposited_value: u128,
+    /// Deposit leg values weighted by each pool's `loan_to_value_bps`.
+    pub allowed_borrow_value: u128,
+    /// Deposit leg values weighted by each pool's `liquidation_threshold_bps`.
+    pub unhealthy_borrow_value: u128,
+    /// Market value of the debt in all borrow legs.
+    pub borrowed_value: u128,
+    pub deposit_count: u8,
+    pub borrow_count: u8,
+    /// Pools whose collateral backs this obligation, a subset of `MarketConfig.pools`.
+    pub deposits: [Pubkey; Obligation::MAX_DEPOSITS],
+    /// Pools whose debt is counted against this obligation, a subset of `MarketConfig.pools`.
+    pub borrows: [Pubkey; Obligation::MAX_BORROWS],
+    /// Market the obligation belongs to; its legs are all pools of this market.
+    pub market: Pubkey,
+}
+
 // --- 8. Parameters & Enums ---
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, InitSpace)]
 pub struct AssetPoolParams {
     pub loan_to_value_bps: u64,
     pub liquidation_threshold_bps: u64,
@@ -1557,6 +3274,54 @@ pub struct AssetPoolParams {
     pub kink_slope_bps: u128,
     pub protocol_fee_bps: u64,
     pub flash_loan_fee_bps: u64,
+    pub liquidation_bonus_bps: u64,
+    pub close_factor_bps: u64,
+    pub insurance_fee_bps: u64,
+    pub supply_cap: u64,
+    pub borrow_cap: u64,
+    pub max_price_age_seconds: u64,
+    pub max_confidence_bps: u64,
+    /// Largest gap between the primary and fallback prices, in bps; zero disables the check.
+    pub max_oracle_deviation_bps: u64,
+    /// Value collateral at `price - conf` and debt at `price + conf` in health checks.
+    pub conservative_pricing: bool,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MarketRole {
+    Guardian,
+    RiskAdmin,
+    FeeCollector,
+    FeeTreasury,
+}
+
+/// Price provider a pool reads from one of its feed accounts.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OracleSource {
+    /// Legacy Pyth push-oracle price account.
+    Pyth,
+    Chainlink,
+    Switchboard,
+    /// Pyth pull-oracle `PriceUpdateV2` account for the pool's `pyth_feed_id`.
+    PythPull,
+}
+
+impl OracleSource {
+    pub fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Self::Pyth),
+            1 => Ok(Self::Chainlink),
+            2 => Ok(Self::Switchboard),
+            3 => Ok(Self::PythPull),
+            _ => err!(LendingError::InvalidOracleSource),
+        }
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ObligationLegSide {
+    Deposit,
+    Borrow,
 }
 
 #[derive(AnchorSerialize, AnchorDeserialize, Clone)]
@@ -1569,60 +3334,177 @@ pub enum Operation {
 
 // --- 9. Logic Implementations & Helpers ---
 
+impl<'info> SocializeBadDebt<'info> {
+    /// Burns whatever collateral is left in the borrower's deposit legs. The tokens stay in
+    /// each collateral vault and are credited to that pool's protocol fees.
+    pub fn seize_remaining_collateral(
+        &self,
+        deposit_legs: &[Pubkey],
+        accounts: &'info [AccountInfo<'info>],
+    ) -> Result<()> {
+        require_eq!(
+            accounts.len(),
+            deposit_legs.len() * ACCOUNTS_PER_SEIZED_LEG,
+            LendingError::InvalidPortfolioAccounts
+        );
+        let borrower = self.borrower.key();
+        let token_program = self.token_program.to_account_info();
+
+        for (pool_key, group) in deposit_legs
+            .iter()
+            .zip(accounts.chunks(ACCOUNTS_PER_SEIZED_LEG))
+        {
+            let (pool_info, position_info, share_mint_info, share_escrow_info) =
+                (&group[0], &group[1], &group[2], &group[3]);
+            require_keys_eq!(
+                pool_info.key(),
+                *pool_key,
+                LendingError::InvalidPortfolioAccounts
+            );
+            let (expected_position, _) = Pubkey::find_program_address(
+                &[USER_POSITION_SEED, pool_key.as_ref(), borrower.as_ref()],
+                &crate::ID,
+            );
+            require_keys_eq!(
+                position_info.key(),
+                expected_position,
+                LendingError::InvalidPortfolioAccounts
+            );
+            if position_info.data_is_empty() {
+                continue;
+            }
+            let position_loader = AccountLoader::<UserPosition>::try_from(position_info)?;
+            let mut position = position_loader.load_mut()?;
+            let scaled_collateral = position.scaled_collateral;
+            if scaled_collateral == 0 {
+                continue;
+            }
+
+            let pool_loader = AccountLoader::<AssetPool>::try_from(pool_info)?;
+            let mut pool = pool_loader.load_mut()?;
+            require_keys_eq!(
+                share_mint_info.key(),
+                pool.share_mint,
+                LendingError::InvalidPortfolioAccounts
+            );
+            let (expected_escrow, escrow_bump) =
+                Pubkey::find_program_address(&[SHARE_ESCROW_SEED, pool_key.as_ref()], &crate::ID);
+            require_keys_eq!(
+                share_escrow_info.key(),
+                expected_escrow,
+                LendingError::InvalidPortfolioAccounts
+            );
+            pool.accrue_interest()?;
+            let amount = pool.collateral_from_scaled(scaled_collateral)?;
+
+            let escrow_seeds = &[SHARE_ESCROW_SEED, pool_key.as_ref(), &[escrow_bump]];
+            cpi_utils::burn_shares(
+                share_mint_info,
+                share_escrow_info,
+                &token_program,
+                &[&escrow_seeds[..]],
+                scaled_collateral,
+            )?;
+            position.scaled_collateral = 0;
+            pool.total_deposits = pool
+                .total_deposits
+                .checked_sub(amount)
+                .ok_or(LendingError::MathOverflow)?;
+            pool.accrued_protocol_fees = pool.accrued_protocol_fees.saturating_add(amount);
+
+            emit!(BadDebtCollateralSeized {
+                pool: *pool_key,
+                borrower,
+                amount,
+            });
+        }
+        Ok(())
+    }
+}
+
 impl<'info> Liquidate<'info> {
+    /// Checks the borrower's refreshed obligation against the liquidation thresholds. The
+    /// cached values already use each pool's `valuation_price`.
     pub fn is_liquidatable(&self) -> Result<bool> {
+        let obligation = self.obligation.load()?;
+        obligation.require_fresh()?;
+        let portfolio = obligation.value();
+        if portfolio.borrowed_value == 0 {
+            return Ok(false);
+        }
+        Ok(!utils::is_healthy(&portfolio))
+    }
+
+    /// The loan pool's close factor, raised to 100% for dust and deeply underwater obligations.
+    pub fn close_factor_bps(&self) -> Result<u64> {
+        let market_config = self.market_config.load()?;
+        let portfolio = self.obligation.load()?.value();
+        let is_dust = portfolio.borrowed_value < market_config.liquidation_dust_value;
+        let is_deeply_underwater = utils::health_factor_bps(&portfolio)?
+            < market_config.full_liquidation_health_factor_bps as u128;
+        if is_dust || is_deeply_underwater {
+            return Ok(BASIS_POINTS_DIVISOR as u64);
+        }
+        Ok(self.loan_pool.load()?.close_factor_bps)
+    }
+
+    /// Inverse of `calculate_liquidation_bonus`: the repay amount that seizes `collateral_amount`.
+    pub fn calculate_repay_for_collateral(&self, collateral_amount: u64) -> Result<u64> {
+        let loan_price = oracle::get_price(
+            &self.loan_price_feed_account,
+            Option::from(self.loan_chainlink_feed_account.as_ref()),
+            self.loan_pool.load()?.oracle_config()?,
+        )?;
         let collateral_price = oracle::get_price(
             &self.collateral_price_feed_account,
             Option::from(self.collateral_chainlink_feed_account.as_ref()),
-        )?;
-        let loan_price = oracle::get_price(
-            &self.loan_price_feed_account,
-            Option::from(&self.loan_chainlink_feed_account),
+            self.collateral_pool.load()?.oracle_config()?,
         )?;
 
+        let liquidation_bonus_bps = self.collateral_pool.load()?.liquidation_bonus_bps as u128;
         let collateral_value = utils::calculate_asset_value(
-            self.borrower_collateral_position.load()?.collateral_amount,
+            collateral_amount,
+            self.collateral_mint.decimals,
             collateral_price,
         )?;
-        let loan_value = utils::calculate_asset_value(
-            self.borrower_loan_position.load()?.loan_amount,
-            loan_price,
-        )?;
-
-        if loan_value == 0 {
-            return Ok(false);
-        }
-
-        let liquidation_threshold = self.collateral_pool.load()?.liquidation_threshold_bps as u128;
-        let threshold_value = collateral_value
-            .checked_mul(liquidation_threshold)
-            .and_then(|v| v.checked_div(BASIS_POINTS_DIVISOR))
+        let repay_value = collateral_value
+            .checked_mul(BASIS_POINTS_DIVISOR)
+            .and_then(|v| v.checked_div(BASIS_POINTS_DIVISOR + liquidation_bonus_bps))
             .ok_or(LendingError::MathOverflow)?;
 
-        Ok(loan_value > threshold_value)
+        utils::calculate_amount_from_value(repay_value, self.loan_mint.decimals, loan_price)
     }
 
     pub fn calculate_liquidation_bonus(&self, repay_amount: u64) -> Result<u64> {
         let loan_price = oracle::get_price(
             &self.loan_price_feed_account,
             Option::from(self.loan_chainlink_feed_account.as_ref()),
+            self.loan_pool.load()?.oracle_config()?,
         )?;
         let collateral_price = oracle::get_price(
             &self.collateral_price_feed_account,
             Option::from(self.collateral_chainlink_feed_account.as_ref()),
+            self.collateral_pool.load()?.oracle_config()?,
         )?;
 
-        let repay_value = utils::calculate_asset_value(repay_amount, loan_price)?;
+        // The bonus is set on the collateral pool, since it prices the risk of the seized asset
+        let liquidation_bonus_bps = self.collateral_pool.load()?.liquidation_bonus_bps as u128;
+        let repay_value =
+            utils::calculate_asset_value(repay_amount, self.loan_mint.decimals, loan_price)?;
         let total_value_to_seize = repay_value
             .checked_mul(
                 BASIS_POINTS_DIVISOR
-                    .checked_add(LIQUIDATION_BONUS_BPS)
+                    .checked_add(liquidation_bonus_bps)
                     .ok_or(LendingError::MathOverflow)?,
             )
             .and_then(|v| v.checked_div(BASIS_POINTS_DIVISOR))
             .ok_or(LendingError::MathOverflow)?;
 
-        utils::calculate_amount_from_value(total_value_to_seize, collateral_price)
+        utils::calculate_amount_from_value(
+            total_value_to_seize,
+            self.collateral_mint.decimals,
+            collateral_price,
+        )
     }
 }
 
@@ -1655,6 +3537,34 @@ impl AssetPool {
             params.optimal_utilization_bps < BASIS_POINTS_DIVISOR as u64,
             LendingError::InvalidOptimalUtilization
         );
+        // Seizing threshold-weighted collateral plus the bonus must not exceed the collateral itself
+        let max_seize_bps = (params.liquidation_threshold_bps as u128)
+            .checked_mul(BASIS_POINTS_DIVISOR + params.liquidation_bonus_bps as u128)
+            .ok_or(LendingError::MathOverflow)?;
+        require!(
+            max_seize_bps <= BASIS_POINTS_DIVISOR * BASIS_POINTS_DIVISOR,
+            LendingError::InvalidLiquidationBonus
+        );
+        require!(
+            params.close_factor_bps > 0 && params.close_factor_bps <= BASIS_POINTS_DIVISOR as u64,
+            LendingError::InvalidCloseFactor
+        );
+        require!(
+            params.protocol_fee_bps as u128 + params.insurance_fee_bps as u128
+                <= BASIS_POINTS_DIVISOR,
+            LendingError::InvalidFeeSplit
+        );
+        require!(
+            params.max_price_age_seconds > 0
+                && params.max_confidence_bps <= BASIS_POINTS_DIVISOR as u64,
+            LendingError::InvalidOracleLimits
+        );
+        require!(
+            params.max_oracle_deviation_bps <= BASIS_POINTS_DIVISOR as u64
+                && (params.max_oracle_deviation_bps == 0
+                    || self.chainlink_price_feed != anchor_lang::system_program::ID),
+            LendingError::InvalidOracleDeviation
+        );
 
         self.loan_to_value_bps = params.loan_to_value_bps;
         self.liquidation_threshold_bps = params.liquidation_threshold_bps;
@@ -1664,6 +3574,174 @@ impl AssetPool {
         self.kink_slope_bps = params.kink_slope_bps;
         self.protocol_fee_bps = params.protocol_fee_bps;
         self.flash_loan_fee_bps = params.flash_loan_fee_bps;
+        self.liquidation_bonus_bps = params.liquidation_bonus_bps;
+        self.close_factor_bps = params.close_factor_bps;
+        self.insurance_fee_bps = params.insurance_fee_bps;
+        self.supply_cap = params.supply_cap;
+        self.borrow_cap = params.borrow_cap;
+        self.max_price_age_seconds = params.max_price_age_seconds;
+        self.max_confidence_bps = params.max_confidence_bps;
+        self.max_oracle_deviation_bps = params.max_oracle_deviation_bps;
+        self.conservative_pricing = params.conservative_pricing as u8;
+        Ok(())
+    }
+
+    /// Oracle providers and the freshness and confidence limits for pricing this pool's asset.
+    pub fn oracle_config(&self) -> Result<oracle::OracleConfig> {
+        Ok(oracle::OracleConfig {
+            primary: OracleSource::from_u8(self.primary_oracle)?,
+            fallback: OracleSource::from_u8(self.fallback_oracle)?,
+            pyth_feed_id: self.pyth_feed_id,
+            max_age_seconds: self.max_price_age_seconds,
+            max_confidence_bps: self.max_confidence_bps,
+            max_deviation_bps: self.max_oracle_deviation_bps,
+        })
+    }
+
+    /// Returns true if `params` can only make the pool safer: LTV, liquidation bonus and
+    /// oracle limits do not rise, caps do not rise or become unlimited, conservative pricing
+    /// is not switched off, and everything else is unchanged.
+    pub fn is_risk_reducing(&self, params: &AssetPoolParams) -> bool {
+        params.loan_to_value_bps <= self.loan_to_value_bps
+            && params.liquidation_bonus_bps <= self.liquidation_bonus_bps
+            && Self::limit_not_raised(self.supply_cap, params.supply_cap)
+            && Self::limit_not_raised(self.borrow_cap, params.borrow_cap)
+            && params.liquidation_threshold_bps == self.liquidation_threshold_bps
+            && params.close_factor_bps == self.close_factor_bps
+            && params.base_borrow_rate_bps == self.base_borrow_rate_bps
+            && params.base_slope_bps == self.base_slope_bps
+            && params.optimal_utilization_bps == self.optimal_utilization_bps
+            && params.kink_slope_bps == self.kink_slope_bps
+            && params.protocol_fee_bps == self.protocol_fee_bps
+            && params.flash_loan_fee_bps == self.flash_loan_fee_bps
+            && params.insurance_fee_bps == self.insurance_fee_bps
+            && params.max_price_age_seconds <= self.max_price_age_seconds
+            && params.max_confidence_bps <= self.max_confidence_bps
+            && Self::limit_not_raised(
+                self.max_oracle_deviation_bps,
+                params.max_oracle_deviation_bps,
+            )
+            && (params.conservative_pricing || !self.is_conservative_pricing())
+    }
+
+    /// Returns true if a risk admin may apply `params`: fees are unchanged; caps, oracle limits
+    /// and conservative pricing are not loosened; and LTV, liquidation threshold and bonus,
+    /// close factor and the interest-rate model each move by at most `RISK_ADMIN_MAX_STEP_BPS`.
+    pub fn is_within_risk_admin_bounds(&self, params: &AssetPoolParams) -> bool {
+        let within_step = |current: u64, new: u64| current.abs_diff(new) <= RISK_ADMIN_MAX_STEP_BPS;
+        let within_rate_step =
+            |current: u128, new: u128| current.abs_diff(new) <= RISK_ADMIN_MAX_STEP_BPS as u128;
+        within_step(self.loan_to_value_bps, params.loan_to_value_bps)
+            && within_step(
+                self.liquidation_threshold_bps,
+                params.liquidation_threshold_bps,
+            )
+            && within_step(self.liquidation_bonus_bps, params.liquidation_bonus_bps)
+            && within_step(self.close_factor_bps, params.close_factor_bps)
+            && within_step(self.optimal_utilization_bps, params.optimal_utilization_bps)
+            && within_rate_step(self.base_borrow_rate_bps, params.base_borrow_rate_bps)
+            && within_rate_step(self.base_slope_bps, params.base_slope_bps)
+            && within_rate_step(self.kink_slope_bps, params.kink_slope_bps)
+            && Self::limit_not_raised(self.supply_cap, params.supply_cap)
+            && Self::limit_not_raised(self.borrow_cap, params.borrow_cap)
+            && params.protocol_fee_bps == self.protocol_fee_bps
+            && params.flash_loan_fee_bps == self.flash_loan_fee_bps
+            && params.insurance_fee_bps == self.insurance_fee_bps
+            && params.max_price_age_seconds <= self.max_price_age_seconds
+            && params.max_confidence_bps <= self.max_confidence_bps
+            && Self::limit_not_raised(
+                self.max_oracle_deviation_bps,
+                params.max_oracle_deviation_bps,
+            )
+            && (params.conservative_pricing || !self.is_conservative_pricing())
+    }
+
+    /// True if `new` is no looser than `current` for a limit where zero means unlimited.
+    fn limit_not_raised(current: u64, new: u64) -> bool {
+        new == current || (new != 0 && (current == 0 || new < current))
+    }
+
+    /// Fails if governance has paused `action` (a `PAUSE_*` flag) on this pool, or if the
+    /// pool is being delisted and `action` would add to its deposits or loans.
+    pub fn require_action_enabled(&self, action: u8) -> Result<()> {
+        require!(
+            self.paused_actions & action == 0,
+            LendingError::PoolActionPaused
+        );
+        require!(
+            !self.is_reduce_only() || action & REDUCE_ONLY_BLOCKED_ACTIONS == 0,
+            LendingError::PoolReduceOnly
+        );
+        Ok(())
+    }
+
+    pub fn is_reduce_only(&self) -> bool {
+        self.status == 1
+    }
+
+    pub fn is_conservative_pricing(&self) -> bool {
+        self.conservative_pricing == 1
+    }
+
+    /// The price health checks use for one side of this pool: with conservative pricing,
+    /// collateral is marked down and debt marked up by the oracle's confidence interval.
+    pub fn valuation_price(&self, price: Price, side: ObligationLegSide) -> Price {
+        if !self.is_conservative_pricing() {
+            return price;
+        }
+        let conf = i64::try_from(price.conf).unwrap_or(i64::MAX);
+        let adjusted = match side {
+            ObligationLegSide::Deposit => price.price.saturating_sub(conf).max(0),
+            ObligationLegSide::Borrow => price.price.saturating_add(conf),
+        };
+        Price {
+            price: adjusted,
+            ..price
+        }
+    }
+
+    /// Adds a new deposit to `total_deposits`, enforcing the supply cap.
+    pub fn add_deposits(&mut self, amount: u64) -> Result<()> {
+        let total_deposits = self
+            .total_deposits
+            .checked_add(amount)
+            .ok_or(LendingError::MathOverflow)?;
+        require!(
+            self.supply_cap == 0 || total_deposits <= self.supply_cap,
+            LendingError::SupplyCapExceeded
+        );
+        self.total_deposits = total_deposits;
+        Ok(())
+    }
+
+    /// Adds a new borrow of `amount` (`scaled_amount` scaled) to the pool totals, enforcing
+    /// the borrow cap.
+    pub fn add_loans(&mut self, amount: u64, scaled_amount: u64) -> Result<()> {
+        let total_loans = self
+            .total_loans
+            .checked_add(amount)
+            .ok_or(LendingError::MathOverflow)?;
+        require!(
+            self.borrow_cap == 0 || total_loans <= self.borrow_cap,
+            LendingError::BorrowCapExceeded
+        );
+        self.total_loans = total_loans;
+        self.total_scaled_loans = self
+            .total_scaled_loans
+            .checked_add(scaled_amount)
+            .ok_or(LendingError::MathOverflow)?;
+        Ok(())
+    }
+
+    /// Removes a repaid or written-off debt of `amount` (`scaled_amount` scaled) from the
+    /// pool totals. Individual debts round up, so `total_loans` may trail their sum by a few
+    /// units and saturates at zero.
+    pub fn remove_loans(&mut self, amount: u64, scaled_amount: u64) -> Result<()> {
+        self.total_loans = self.total_loans.saturating_sub(amount);
+        self.total_scaled_loans = self
+            .total_scaled_loans
+            .checked_sub(scaled_amount)
+            .ok_or(LendingError::MathOverflow)?;
         Ok(())
     }
     /// Accrues interest for the pool based on the current utilization rate.
@@ -1708,20 +3786,94 @@ impl AssetPool {
             .and_then(|v| v.checked_div(U192::from(BASIS_POINTS_DIVISOR)))
             .ok_or(LendingError::MathOverflow)?;
         let total_interest = total_interest_precise.as_u64();
+        if total_interest == 0 {
+            // Less than one base unit so far: keep the timestamp so the interest carries
+            // forward, rather than letting frequent updates round it away.
+            msg!("accrue_interest: interest below one unit. Deferring.");
+            return Ok(());
+        }
         let protocol_fee = U192::from(total_interest)
             .checked_mul(U192::from(self.protocol_fee_bps))
             .and_then(|v| v.checked_div(U192::from(BASIS_POINTS_DIVISOR)))
             .map(|v| v.as_u64())
             .ok_or(LendingError::MathOverflow)?;
-        let lender_interest = total_interest.saturating_sub(protocol_fee);
+        let insurance_fee = U192::from(total_interest)
+            .checked_mul(U192::from(self.insurance_fee_bps))
+            .and_then(|v| v.checked_div(U192::from(BASIS_POINTS_DIVISOR)))
+            .map(|v| v.as_u64())
+            .ok_or(LendingError::MathOverflow)?;
+        let lender_interest = total_interest
+            .saturating_sub(protocol_fee)
+            .saturating_sub(insurance_fee);
+
+        // Grow each index by the same factor as its pool total, so that the sum of all
+        // indexed position balances keeps tracking `total_loans` and `total_deposits`.
+        let new_total_loans = self.total_loans.saturating_add(total_interest);
+        self.cumulative_borrow_index = U192::from(self.cumulative_borrow_index)
+            .checked_mul(U192::from(new_total_loans))
+            .and_then(|v| v.checked_div(U192::from(self.total_loans)))
+            .map(|v| v.as_u128())
+            .ok_or(LendingError::MathOverflow)?;
+        if self.total_deposits > 0 {
+            let new_total_deposits = self.total_deposits.saturating_add(lender_interest);
+            self.cumulative_supply_index = U192::from(self.cumulative_supply_index)
+                .checked_mul(U192::from(new_total_deposits))
+                .and_then(|v| v.checked_div(U192::from(self.total_deposits)))
+                .map(|v| v.as_u128())
+                .ok_or(LendingError::MathOverflow)?;
+        }
 
         self.accrued_protocol_fees = self.accrued_protocol_fees.saturating_add(protocol_fee);
+        self.accrued_insurance_fees = self.accrued_insurance_fees.saturating_add(insurance_fee);
         self.total_deposits = self.total_deposits.saturating_add(lender_interest);
-        self.total_loans = self.total_loans.saturating_add(total_interest);
+        self.total_loans = new_total_loans;
         self.last_interest_update_timestamp = now;
         Ok(())
     }
 
+    /// Absorbs a written-off debt, first out of the insurance reserve (accrued insurance fees,
+    /// then `reserve_balance` held in the insurance vault), then out of accrued protocol fees,
+    /// and finally by lowering the supply index so every supplier's balance shrinks pro rata.
+    ///
+    /// Returns the amounts covered by the reserve, covered by fees, and socialized.
+    pub fn absorb_bad_debt(
+        &mut self,
+        amount: u64,
+        reserve_balance: u64,
+    ) -> Result<(u64, u64, u64)> {
+        let reserve = self.accrued_insurance_fees.saturating_add(reserve_balance);
+        let covered_by_reserve = amount.min(reserve);
+        self.accrued_insurance_fees = self
+            .accrued_insurance_fees
+            .saturating_sub(covered_by_reserve);
+
+        let covered_by_fees = (amount - covered_by_reserve).min(self.accrued_protocol_fees);
+        self.accrued_protocol_fees -= covered_by_fees;
+
+        let socialized = (amount - covered_by_reserve - covered_by_fees).min(self.total_deposits);
+        if socialized > 0 {
+            let new_total_deposits = self.total_deposits - socialized;
+            // Keep the index non-zero so later deposits can still be scaled
+            self.cumulative_supply_index = U192::from(self.cumulative_supply_index)
+                .checked_mul(U192::from(new_total_deposits))
+                .and_then(|v| v.checked_div(U192::from(self.total_deposits)))
+                .map(|v| v.as_u128().max(1))
+                .ok_or(LendingError::MathOverflow)?;
+            self.total_deposits = new_total_deposits;
+        }
+        Ok((covered_by_reserve, covered_by_fees, socialized))
+    }
+
+    /// Returns the underlying amount a scaled collateral balance is currently worth.
+    pub fn collateral_from_scaled(&self, scaled_collateral: u64) -> Result<u64> {
+        utils::from_scaled(scaled_collateral, self.cumulative_supply_index, false)
+    }
+
+    /// Returns the debt currently owed for a scaled loan balance, rounded up.
+    pub fn debt_from_scaled(&self, scaled_loan: u64) -> Result<u64> {
+        utils::from_scaled(scaled_loan, self.cumulative_borrow_index, true)
+    }
+
     /// Calculates the current APY based on the kinked interest rate model.
     fn calculate_current_apy(&self, utilization_bps: u128) -> Result<u128> {
         let optimal_util_bps = self.optimal_utilization_bps as u128;
@@ -1753,6 +3905,218 @@ impl AssetPool {
     }
 }
 
+impl UserPosition {
+    /// Fills in a position that `init_if_needed` has just created, as `create_user_position`
+    /// does, and checks the owner of one that already existed.
+    ///
+    /// Anchor only writes the discriminator of a new zero-copy account on exit, so it is written
+    /// here to let the rest of the instruction `load_mut` the position.
+    pub fn init_if_new(
+        loader: &AccountLoader<UserPosition>,
+        owner: Pubkey,
+        pool: Pubkey,
+    ) -> Result<()> {
+        let info = loader.as_ref();
+        let is_new = info.try_borrow_data()?[..8] == [0u8; 8];
+        if is_new {
+            {
+                let position = &mut loader.load_init()?;
+                position.owner = owner;
+                position.pool = pool;
+                position.scaled_collateral = 0;
+                position.scaled_loan = 0;
+            }
+            info.try_borrow_mut_data()?[..8].copy_from_slice(UserPosition::DISCRIMINATOR);
+        }
+        require_keys_eq!(loader.load()?.owner, owner, LendingError::InvalidOwner);
+        Ok(())
+    }
+}
+
+impl Obligation {
+    pub const MAX_DEPOSITS: usize = 8;
+    pub const MAX_BORROWS: usize = 8;
+
+    /// Pools of the open legs on `side`, in leg order.
+    pub fn legs(&self, side: ObligationLegSide) -> Vec<Pubkey> {
+        match side {
+            ObligationLegSide::Deposit => {
+                let deposits = self.deposits;
+                deposits[..self.deposit_count as usize].to_vec()
+            }
+            ObligationLegSide::Borrow => {
+                let borrows = self.borrows;
+                borrows[..self.borrow_count as usize].to_vec()
+            }
+        }
+    }
+
+    pub fn has_leg(&self, side: ObligationLegSide, pool: &Pubkey) -> bool {
+        self.legs(side).contains(pool)
+    }
+
+    pub fn open_leg(&mut self, side: ObligationLegSide, pool: Pubkey) -> Result<()> {
+        require!(
+            !self.has_leg(side, &pool),
+            LendingError::ObligationLegAlreadyOpen
+        );
+        match side {
+            ObligationLegSide::Deposit => {
+                require!(
+                    (self.deposit_count as usize) < Self::MAX_DEPOSITS,
+                    LendingError::ObligationLegsFull
+                );
+                self.deposits[self.deposit_count as usize] = pool;
+                self.deposit_count += 1;
+            }
+            ObligationLegSide::Borrow => {
+                require!(
+                    (self.borrow_count as usize) < Self::MAX_BORROWS,
+                    LendingError::ObligationLegsFull
+                );
+                self.borrows[self.borrow_count as usize] = pool;
+                self.borrow_count += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes a leg, shifting the later legs down so the open legs stay contiguous.
+    pub fn close_leg(&mut self, side: ObligationLegSide, pool: Pubkey) -> Result<()> {
+        let mut legs = self.legs(side);
+        let index = legs
+            .iter()
+            .position(|leg| *leg == pool)
+            .ok_or(LendingError::ObligationLegNotFound)?;
+        legs.remove(index);
+        match side {
+            ObligationLegSide::Deposit => {
+                let mut deposits = [Pubkey::default(); Self::MAX_DEPOSITS];
+                deposits[..legs.len()].copy_from_slice(&legs);
+                self.deposits = deposits;
+                self.deposit_count -= 1;
+            }
+            ObligationLegSide::Borrow => {
+                let mut borrows = [Pubkey::default(); Self::MAX_BORROWS];
+                borrows[..legs.len()].copy_from_slice(&legs);
+                self.borrows = borrows;
+                self.borrow_count -= 1;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn require_fresh(&self) -> Result<()> {
+        require!(
+            self.last_update_slot == Clock::get()?.slot,
+            LendingError::ObligationStale
+        );
+        Ok(())
+    }
+
+    pub fn mark_stale(&mut self) {
+        self.last_update_slot = 0;
+    }
+
+    pub fn value(&self) -> utils::PortfolioValue {
+        utils::PortfolioValue {
+            deposited_value: self.deposited_value,
+            allowed_borrow_value: self.allowed_borrow_value,
+            unhealthy_borrow_value: self.unhealthy_borrow_value,
+            borrowed_value: self.borrowed_value,
+        }
+    }
+
+    pub fn set_value(&mut self, portfolio: &utils::PortfolioValue) {
+        self.deposited_value = portfolio.deposited_value;
+        self.allowed_borrow_value = portfolio.allowed_borrow_value;
+        self.unhealthy_borrow_value = portfolio.unhealthy_borrow_value;
+        self.borrowed_value = portfolio.borrowed_value;
+    }
+
+    /// Checks that a new borrow of `amount` from `pool` stays within the borrow limit and
+    /// adds it to the cached values, so later checks in the same slot see it.
+    pub fn apply_borrow(
+        &mut self,
+        pool_key: &Pubkey,
+        pool: &AssetPool,
+        amount: u64,
+        price: Price,
+    ) -> Result<()> {
+        require!(
+            self.has_leg(ObligationLegSide::Borrow, pool_key),
+            LendingError::ObligationLegNotFound
+        );
+        self.require_fresh()?;
+        let mut portfolio = self.value();
+        portfolio.add_balances(pool, 0, amount, price)?;
+        require!(
+            utils::is_eligible_for_borrow(&portfolio),
+            LendingError::InsufficientCollateral
+        );
+        self.set_value(&portfolio);
+        Ok(())
+    }
+
+    /// Largest amount of `pool`'s `collateral` that `apply_withdraw` accepts: all of it when
+    /// the pool is not a deposit leg or the obligation has no debt, otherwise as much as the
+    /// cached health headroom allows.
+    pub fn max_withdraw(
+        &self,
+        pool_key: &Pubkey,
+        pool: &AssetPool,
+        collateral: u64,
+        price: Price,
+    ) -> Result<u64> {
+        if !self.has_leg(ObligationLegSide::Deposit, pool_key) {
+            return Ok(collateral);
+        }
+        self.require_fresh()?;
+        let portfolio = self.value();
+        if portfolio.borrowed_value == 0 || pool.liquidation_threshold_bps == 0 {
+            return Ok(collateral);
+        }
+        let headroom = portfolio
+            .unhealthy_borrow_value
+            .saturating_sub(portfolio.borrowed_value);
+        // Collateral value whose threshold-weighted share fits in the headroom
+        let removable_value = U192::from(headroom)
+            .checked_mul(U192::from(BASIS_POINTS_DIVISOR))
+            .and_then(|v| v.checked_div(U192::from(pool.liquidation_threshold_bps)))
+            .ok_or(LendingError::MathOverflow)?;
+        let price = pool.valuation_price(price, ObligationLegSide::Deposit);
+        let collateral_value =
+            utils::calculate_asset_value(collateral, pool.asset_decimals, price)?;
+        if removable_value >= U192::from(collateral_value) {
+            return Ok(collateral);
+        }
+        utils::calculate_amount_from_value(removable_value.as_u128(), pool.asset_decimals, price)
+    }
+
+    /// Checks that withdrawing `amount` from `pool` keeps the obligation healthy and removes
+    /// it from the cached values. Collateral outside the deposit legs backs nothing.
+    pub fn apply_withdraw(
+        &mut self,
+        pool_key: &Pubkey,
+        pool: &AssetPool,
+        amount: u64,
+        price: Price,
+    ) -> Result<()> {
+        if !self.has_leg(ObligationLegSide::Deposit, pool_key) {
+            return Ok(());
+        }
+        self.require_fresh()?;
+        let mut portfolio = self.value();
+        portfolio.remove_collateral(pool, amount, price)?;
+        require!(
+            utils::is_healthy(&portfolio),
+            LendingError::PositionWouldBecomeUnhealthy
+        );
+        self.set_value(&portfolio);
+        Ok(())
+    }
+}
+
 // --- 10. Helper Modules ---
 
 pub mod cpi_utils {
@@ -1775,6 +4139,7 @@ pub mod cpi_utils {
         let cpi_context = CpiContext::new(token_program.clone(), cpi_accounts);
         token_interface::transfer_checked(cpi_context, amount, decimals)
     }
+    #[allow(clippy::too_many_arguments)]
     pub fn transfer_from_vault_checked<'info>(
         from: &AccountInfo<'info>,
         to: &AccountInfo<'info>,
@@ -1795,6 +4160,56 @@ pub mod cpi_utils {
             CpiContext::new(token_program.clone(), cpi_accounts).with_signer(signer_seeds);
         token_interface::transfer_checked(cpi_context, amount, decimals)
     }
+    /// Mints pool shares; the share mint PDA is its own mint authority.
+    pub fn mint_shares<'info>(
+        share_mint: &AccountInfo<'info>,
+        to: &AccountInfo<'info>,
+        token_program: &AccountInfo<'info>,
+        signer_seeds: &[&[&[u8]]],
+        shares: u64,
+    ) -> Result<()> {
+        let cpi_accounts = MintTo {
+            mint: share_mint.clone(),
+            to: to.clone(),
+            authority: share_mint.clone(),
+        };
+        let cpi_context =
+            CpiContext::new(token_program.clone(), cpi_accounts).with_signer(signer_seeds);
+        token_interface::mint_to(cpi_context, shares)
+    }
+    /// Closes a pool token account that is its own authority, sending its rent to `destination`.
+    pub fn close_vault<'info>(
+        vault: &AccountInfo<'info>,
+        destination: &AccountInfo<'info>,
+        token_program: &AccountInfo<'info>,
+        signer_seeds: &[&[&[u8]]],
+    ) -> Result<()> {
+        let cpi_accounts = CloseAccount {
+            account: vault.clone(),
+            destination: destination.clone(),
+            authority: vault.clone(),
+        };
+        let cpi_context =
+            CpiContext::new(token_program.clone(), cpi_accounts).with_signer(signer_seeds);
+        token_interface::close_account(cpi_context)
+    }
+    /// Burns pool shares out of the share escrow, which is its own token authority.
+    pub fn burn_shares<'info>(
+        share_mint: &AccountInfo<'info>,
+        share_escrow: &AccountInfo<'info>,
+        token_program: &AccountInfo<'info>,
+        signer_seeds: &[&[&[u8]]],
+        shares: u64,
+    ) -> Result<()> {
+        let cpi_accounts = Burn {
+            mint: share_mint.clone(),
+            from: share_escrow.clone(),
+            authority: share_escrow.clone(),
+        };
+        let cpi_context =
+            CpiContext::new(token_program.clone(), cpi_accounts).with_signer(signer_seeds);
+        token_interface::burn(cpi_context, shares)
+    }
 }
 
 pub mod oracle {
@@ -1810,16 +4225,116 @@ pub mod oracle {
         pub timestamp: i64,
     }
 
+    /// Switchboard On-Demand program that owns pull-feed accounts.
+    #[cfg(not(feature = "test-oracle"))]
+    const SWITCHBOARD_ON_DEMAND_ID: Pubkey = pubkey!("SBondMDrcV3K4kxZR1HNVT7osZxAHVHgYXL5Ze1oMUv");
+
+    /// Anchor discriminator of Switchboard's `PullFeedAccountData`.
+    pub const SWITCHBOARD_PULL_FEED_DISCRIMINATOR: [u8; 8] = [196, 27, 108, 196, 10, 215, 219, 40];
+
+    /// Pyth receiver program that owns verified `PriceUpdateV2` accounts.
+    #[cfg(not(feature = "test-oracle"))]
+    const PYTH_RECEIVER_ID: Pubkey = pubkey!("rec5EKMGg6MxZYaMdyBfgwp4d5rB9T1VQH5pJv5LtFJ");
+
+    /// Anchor discriminator of Pyth's `PriceUpdateV2`.
+    pub const PYTH_PRICE_UPDATE_V2_DISCRIMINATOR: [u8; 8] = [34, 241, 35, 99, 157, 126, 244, 205];
+
+    /// Borsh tag of `VerificationLevel::Full`; `Partial` is tag 0 followed by a signature count.
+    const PYTH_VERIFICATION_LEVEL_FULL: u8 = 1;
+
+    /// Switchboard results are fixed-point with 18 decimals.
+    const SWITCHBOARD_DECIMALS: i32 = 18;
+
+    /// Leading part of Switchboard's `PullFeedAccountData`, after the discriminator, up to and
+    /// including the current result. Only the fields the protocol reads are named.
+    #[repr(C, packed)]
+    #[derive(Clone, Copy, Debug, Pod, Zeroable)]
+    pub struct SwitchboardPullFeed {
+        _submissions: [[u8; 64]; 32],
+        pub authority: Pubkey,
+        pub queue: Pubkey,
+        pub feed_hash: [u8; 32],
+        pub initialized_at: i64,
+        pub permissions: u64,
+        pub max_variance: u64,
+        pub min_responses: u32,
+        pub name: [u8; 32],
+        _padding0: [u8; 1],
+        pub permit_write_by_authority: u8,
+        pub historical_result_idx: u8,
+        pub min_sample_size: u8,
+        pub last_update_timestamp: i64,
+        pub lut_slot: u64,
+        _reserved0: [u8; 32],
+        pub result: SwitchboardCurrentResult,
+    }
+
+    #[repr(C, packed)]
+    #[derive(Clone, Copy, Debug, Pod, Zeroable)]
+    pub struct SwitchboardCurrentResult {
+        pub value: i128,
+        pub std_dev: i128,
+        pub mean: i128,
+        pub range: i128,
+        pub min_value: i128,
+        pub max_value: i128,
+        pub num_samples: u8,
+        pub submission_idx: u8,
+        _padding: [u8; 6],
+        pub slot: u64,
+        pub min_slot: u64,
+        pub max_slot: u64,
+    }
+
+    /// `PriceFeedMessage` of a fully verified `PriceUpdateV2`, which follows the write
+    /// authority and the one-byte `Full` verification level.
+    #[repr(C, packed)]
+    #[derive(Clone, Copy, Debug, Pod, Zeroable)]
+    pub struct PythPriceFeedMessage {
+        pub feed_id: [u8; 32],
+        pub price: i64,
+        pub conf: u64,
+        pub exponent: i32,
+        pub publish_time: i64,
+        pub prev_publish_time: i64,
+        pub ema_price: i64,
+        pub ema_conf: u64,
+    }
+
+    /// How a pool is priced; see `AssetPool::oracle_config`.
+    #[derive(Clone, Copy, Debug)]
+    pub struct OracleConfig {
+        pub primary: OracleSource,
+        pub fallback: OracleSource,
+        pub pyth_feed_id: [u8; 32],
+        pub max_age_seconds: u64,
+        /// Bounds the Pyth confidence interval and the Switchboard standard deviation;
+        /// Chainlink answers are taken as exact.
+        pub max_confidence_bps: u64,
+        /// When non-zero, both feeds are read and must agree within this many bps.
+        pub max_deviation_bps: u64,
+    }
+
     pub fn get_price<'info>(
-        pyth_feed_account: &AccountInfo<'info>,
-        chainlink_feed_account: Option<&AccountInfo<'info>>, // <-- 核心修复：改为 Option
+        primary_feed_account: &AccountInfo<'info>,
+        fallback_feed_account: Option<&AccountInfo<'info>>, // <-- 核心修复：改为 Option
+        config: OracleConfig,
     ) -> Result<Price> {
-        if let Ok(price) = get_price_from_pyth(pyth_feed_account) {
+        if config.max_deviation_bps > 0 {
+            let fallback_feed_account =
+                fallback_feed_account.ok_or(LendingError::AllOraclesFailed)?;
+            let price = get_price_from(config.primary, primary_feed_account, config)?;
+            let fallback_price = get_price_from(config.fallback, fallback_feed_account, config)?;
+            require_deviation_within(&price, &fallback_price, config.max_deviation_bps)?;
+            return Ok(price);
+        }
+
+        if let Ok(price) = get_price_from(config.primary, primary_feed_account, config) {
             return Ok(price);
         }
 
-        if let Some(feed) = chainlink_feed_account {
-            if let Ok(price) = get_price_from_chainlink(feed) {
+        if let Some(feed) = fallback_feed_account {
+            if let Ok(price) = get_price_from(config.fallback, feed, config) {
                 return Ok(price);
             }
         }
@@ -1827,7 +4342,45 @@ pub mod oracle {
         Err(error!(LendingError::AllOraclesFailed))
     }
 
-    fn get_price_from_pyth<'info>(price_feed_info: &AccountInfo<'info>) -> Result<Price> {
+    /// Fails if `a` and `b` differ by more than `max_deviation_bps` of the lower price.
+    fn require_deviation_within(a: &Price, b: &Price, max_deviation_bps: u64) -> Result<()> {
+        // Bring both prices to the smaller exponent so they can be compared directly.
+        let expo = a.expo.min(b.expo);
+        let scale = |price: &Price| -> Result<U192> {
+            let factor = 10u64
+                .checked_pow(price.expo.abs_diff(expo))
+                .ok_or(LendingError::MathOverflow)?;
+            U192::from(price.price.unsigned_abs())
+                .checked_mul(U192::from(factor))
+                .ok_or(LendingError::MathOverflow.into())
+        };
+        let (a, b) = (scale(a)?, scale(b)?);
+        let (low, high) = if a < b { (a, b) } else { (b, a) };
+        let max_gap = low
+            .checked_mul(U192::from(max_deviation_bps))
+            .and_then(|v| v.checked_div(U192::from(BASIS_POINTS_DIVISOR)))
+            .ok_or(LendingError::MathOverflow)?;
+        require!(high - low <= max_gap, LendingError::OracleDeviationTooHigh);
+        Ok(())
+    }
+
+    fn get_price_from<'info>(
+        source: OracleSource,
+        feed_info: &AccountInfo<'info>,
+        config: OracleConfig,
+    ) -> Result<Price> {
+        match source {
+            OracleSource::Pyth => get_price_from_pyth(feed_info, config),
+            OracleSource::Chainlink => get_price_from_chainlink(feed_info, config),
+            OracleSource::Switchboard => get_price_from_switchboard(feed_info, config),
+            OracleSource::PythPull => get_price_from_pyth_pull(feed_info, config),
+        }
+    }
+
+    fn get_price_from_pyth<'info>(
+        price_feed_info: &AccountInfo<'info>,
+        limits: OracleConfig,
+    ) -> Result<Price> {
         // We assume the `test-oracle` feature is enabled for `anchor test`.
         // The production logic branch is kept for completeness.
 
@@ -1892,16 +4445,16 @@ pub mod oracle {
             );
 
             let price = match price_feed
-                .get_price_unchecked(current_timestamp, STALE_PRICE_THRESHOLD_SECONDS)
+                .get_price_no_older_than(current_timestamp, limits.max_age_seconds)
             {
                 Some(p) => p,
                 None => {
                     let time_diff = current_timestamp.saturating_sub(price_account.timestamp);
-                    msg!("Error: get_price_unchecked returned None (price is too old).");
+                    msg!("Error: get_price_no_older_than returned None (price is too old).");
                     msg!(
                         "Time difference: {} seconds. Threshold: {} seconds.",
                         time_diff,
-                        STALE_PRICE_THRESHOLD_SECONDS
+                        limits.max_age_seconds
                     );
                     return Err(LendingError::PythPriceTooOld.into());
                 }
@@ -1926,7 +4479,7 @@ pub mod oracle {
 
             // Use U192 for calculation to avoid overflow, as in the original code
             let max_conf = match U192::from(price_magnitude)
-                .checked_mul(U192::from(MAX_CONFIDENCE_INTERVAL_BPS))
+                .checked_mul(U192::from(limits.max_confidence_bps))
                 .and_then(|v| v.checked_div(U192::from(BASIS_POINTS_DIVISOR)))
                 .map(|v| v.as_u64())
             {
@@ -1967,7 +4520,7 @@ pub mod oracle {
             let current_timestamp = Clock::get()?.unix_timestamp;
 
             let price = match price_feed
-                .get_price_no_older_than(current_timestamp, STALE_PRICE_THRESHOLD_SECONDS)
+                .get_price_no_older_than(current_timestamp, limits.max_age_seconds)
             {
                 Some(p) => p,
                 None => return Err(LendingError::PythPriceTooOld.into()),
@@ -1979,7 +4532,7 @@ pub mod oracle {
 
             let price_magnitude = price.price.unsigned_abs();
             let max_conf = U192::from(price_magnitude)
-                .checked_mul(U192::from(MAX_CONFIDENCE_INTERVAL_BPS))
+                .checked_mul(U192::from(limits.max_confidence_bps))
                 .and_then(|v| v.checked_div(U192::from(BASIS_POINTS_DIVISOR)))
                 .map(|v| v.as_u64())
                 .ok_or(LendingError::MathOverflow)?;
@@ -1991,7 +4544,71 @@ pub mod oracle {
             Ok(price)
         }
     }
-    fn get_price_from_chainlink<'info>(chainlink_feed_info: &AccountInfo<'info>) -> Result<Price> {
+
+    fn get_price_from_pyth_pull<'info>(
+        price_update_info: &AccountInfo<'info>,
+        limits: OracleConfig,
+    ) -> Result<Price> {
+        // Test fixtures are written by `mock_writer`, so the owner is only checked in production.
+        #[cfg(not(feature = "test-oracle"))]
+        require_keys_eq!(
+            *price_update_info.owner,
+            PYTH_RECEIVER_ID,
+            LendingError::InvalidPythAccount
+        );
+
+        let data = price_update_info.try_borrow_data()?;
+        // discriminator + write_authority, then the verification level
+        let level_offset = 8 + 32;
+        let message_offset = level_offset + 1;
+        let message_len = std::mem::size_of::<PythPriceFeedMessage>();
+        require!(
+            data.len() >= message_offset + message_len
+                && data[..8] == PYTH_PRICE_UPDATE_V2_DISCRIMINATOR,
+            LendingError::InvalidPythAccount
+        );
+        require!(
+            data[level_offset] == PYTH_VERIFICATION_LEVEL_FULL,
+            LendingError::PythUpdateNotFullyVerified
+        );
+        let message: &PythPriceFeedMessage =
+            bytemuck::from_bytes(&data[message_offset..message_offset + message_len]);
+
+        require!(
+            message.feed_id == limits.pyth_feed_id,
+            LendingError::PythFeedIdMismatch
+        );
+        require!(
+            Clock::get()?
+                .unix_timestamp
+                .saturating_sub(message.publish_time)
+                <= limits.max_age_seconds as i64,
+            LendingError::PythPriceTooOld
+        );
+        require!(message.price > 0, LendingError::InvalidPythPrice);
+
+        let max_conf = U192::from(message.price.unsigned_abs())
+            .checked_mul(U192::from(limits.max_confidence_bps))
+            .and_then(|v| v.checked_div(U192::from(BASIS_POINTS_DIVISOR)))
+            .map(|v| v.as_u64())
+            .ok_or(LendingError::MathOverflow)?;
+        require!(
+            message.conf <= max_conf,
+            LendingError::PythConfidenceTooWide
+        );
+
+        Ok(Price {
+            price: message.price,
+            conf: message.conf,
+            expo: message.exponent,
+            publish_time: message.publish_time,
+        })
+    }
+
+    fn get_price_from_chainlink<'info>(
+        chainlink_feed_info: &AccountInfo<'info>,
+        limits: OracleConfig,
+    ) -> Result<Price> {
         let data = chainlink_feed_info.try_borrow_data()?;
         require_gte!(
             data.len(),
@@ -2002,7 +4619,7 @@ pub mod oracle {
             bytemuck::from_bytes(&data[..std::mem::size_of::<ChainlinkPriceFeed>()]);
         require!(
             Clock::get()?.unix_timestamp.saturating_sub(feed.timestamp)
-                < STALE_PRICE_THRESHOLD_SECONDS as i64,
+                <= limits.max_age_seconds as i64,
             LendingError::ChainlinkPriceTooOld
         );
         require!(feed.answer > 0, LendingError::InvalidChainlinkPrice);
@@ -2013,78 +4630,365 @@ pub mod oracle {
             publish_time: feed.timestamp,
         })
     }
+
+    fn get_price_from_switchboard<'info>(
+        switchboard_feed_info: &AccountInfo<'info>,
+        limits: OracleConfig,
+    ) -> Result<Price> {
+        // Test fixtures are written by `mock_writer`, so the owner is only checked in production.
+        #[cfg(not(feature = "test-oracle"))]
+        require_keys_eq!(
+            *switchboard_feed_info.owner,
+            SWITCHBOARD_ON_DEMAND_ID,
+            LendingError::InvalidSwitchboardAccount
+        );
+
+        let data = switchboard_feed_info.try_borrow_data()?;
+        let feed_len = std::mem::size_of::<SwitchboardPullFeed>();
+        require!(
+            data.len() >= 8 + feed_len && data[..8] == SWITCHBOARD_PULL_FEED_DISCRIMINATOR,
+            LendingError::InvalidSwitchboardAccount
+        );
+        let feed: &SwitchboardPullFeed = bytemuck::from_bytes(&data[8..8 + feed_len]);
+        let result = feed.result;
+
+        require!(
+            Clock::get()?
+                .unix_timestamp
+                .saturating_sub(feed.last_update_timestamp)
+                <= limits.max_age_seconds as i64,
+            LendingError::SwitchboardPriceTooOld
+        );
+        let mut value = result.value;
+        let mut std_dev = result.std_dev;
+        require!(
+            result.num_samples > 0 && value > 0 && std_dev >= 0,
+            LendingError::InvalidSwitchboardPrice
+        );
+
+        let max_std_dev = U192::from(value as u128)
+            .checked_mul(U192::from(limits.max_confidence_bps))
+            .and_then(|v| v.checked_div(U192::from(BASIS_POINTS_DIVISOR)))
+            .ok_or(LendingError::MathOverflow)?;
+        require!(
+            U192::from(std_dev as u128) <= max_std_dev,
+            LendingError::SwitchboardVarianceTooWide
+        );
+
+        // Drop only as many decimals as needed to fit the value into Pyth's i64 price.
+        let mut expo = -SWITCHBOARD_DECIMALS;
+        while value > i64::MAX as i128 {
+            value /= 10;
+            std_dev /= 10;
+            expo += 1;
+        }
+
+        Ok(Price {
+            price: value as i64,
+            conf: std_dev as u64,
+            expo,
+            publish_time: feed.last_update_timestamp,
+        })
+    }
 }
 
 pub mod utils {
     use super::*;
-    pub fn is_healthy(
-        collateral: u64,
-        debt: u64,
-        price: Price,
-        liquidation_threshold_bps: u64,
-    ) -> Result<bool> {
-        if debt == 0 {
-            return Ok(true);
+
+    /// Aggregated value of a user's positions across asset pools.
+    ///
+    /// Every leg is priced by its own pool's oracle and weighted by that pool's risk
+    /// parameters, so collateral in one asset can back debt in another.
+    #[derive(Clone, Copy, Debug, Default)]
+    pub struct PortfolioValue {
+        /// Market value of all collateral.
+        pub deposited_value: u128,
+        /// Collateral value weighted by each pool's `loan_to_value_bps`.
+        pub allowed_borrow_value: u128,
+        /// Collateral value weighted by each pool's `liquidation_threshold_bps`.
+        pub unhealthy_borrow_value: u128,
+        /// Market value of all debt.
+        pub borrowed_value: u128,
+    }
+
+    impl PortfolioValue {
+        /// Adds one pool's collateral and debt, priced with that pool's `price` as adjusted by
+        /// `AssetPool::valuation_price`.
+        pub fn add_balances(
+            &mut self,
+            pool: &AssetPool,
+            collateral: u64,
+            debt: u64,
+            price: Price,
+        ) -> Result<()> {
+            let decimals = pool.asset_decimals;
+            if collateral > 0 {
+                let collateral_price = pool.valuation_price(price, ObligationLegSide::Deposit);
+                let collateral_value =
+                    calculate_asset_value(collateral, decimals, collateral_price)?;
+                self.deposited_value = self
+                    .deposited_value
+                    .checked_add(collateral_value)
+                    .ok_or(LendingError::MathOverflow)?;
+                self.allowed_borrow_value = self
+                    .allowed_borrow_value
+                    .checked_add(apply_bps(collateral_value, pool.loan_to_value_bps)?)
+                    .ok_or(LendingError::MathOverflow)?;
+                self.unhealthy_borrow_value = self
+                    .unhealthy_borrow_value
+                    .checked_add(apply_bps(collateral_value, pool.liquidation_threshold_bps)?)
+                    .ok_or(LendingError::MathOverflow)?;
+            }
+            if debt > 0 {
+                self.borrowed_value = self
+                    .borrowed_value
+                    .checked_add(calculate_asset_value(
+                        debt,
+                        decimals,
+                        pool.valuation_price(price, ObligationLegSide::Borrow),
+                    )?)
+                    .ok_or(LendingError::MathOverflow)?;
+            }
+            Ok(())
+        }
+
+        /// Removes withdrawn collateral. Saturates at zero, since collateral deposited after
+        /// the last refresh is not part of the cached values yet.
+        pub fn remove_collateral(
+            &mut self,
+            pool: &AssetPool,
+            collateral: u64,
+            price: Price,
+        ) -> Result<()> {
+            let collateral_price = pool.valuation_price(price, ObligationLegSide::Deposit);
+            let collateral_value =
+                calculate_asset_value(collateral, pool.asset_decimals, collateral_price)?;
+            self.deposited_value = self.deposited_value.saturating_sub(collateral_value);
+            self.allowed_borrow_value = self
+                .allowed_borrow_value
+                .saturating_sub(apply_bps(collateral_value, pool.loan_to_value_bps)?);
+            self.unhealthy_borrow_value = self
+                .unhealthy_borrow_value
+                .saturating_sub(apply_bps(collateral_value, pool.liquidation_threshold_bps)?);
+            Ok(())
         }
-        let collateral_value = calculate_asset_value(collateral, price)?;
-        let debt_value = calculate_asset_value(debt, price)?;
-        let max_debt_value = U192::from(collateral_value)
-            .checked_mul(U192::from(liquidation_threshold_bps))
-            .and_then(|v| v.checked_div(U192::from(BASIS_POINTS_DIVISOR)))
-            .map(|v| v.as_u128())
-            .ok_or(LendingError::MathOverflow)?;
-        Ok(debt_value <= max_debt_value)
     }
-    pub fn is_eligible_for_borrow(
-        collateral: u64,
-        new_debt: u64,
-        price: Price,
-        loan_to_value_bps: u64,
-    ) -> Result<bool> {
-        let collateral_value = calculate_asset_value(collateral, price)?;
-        let new_debt_value = calculate_asset_value(new_debt, price)?;
-        let max_borrow_value = U192::from(collateral_value)
-            .checked_mul(U192::from(loan_to_value_bps))
+
+    /// Values an obligation's legs at current prices and projected interest indices.
+    ///
+    /// `accounts` must hold one `[asset_pool, user_position, pyth_feed, chainlink_feed]`
+    /// group per deposit leg, then one per borrow leg, in leg order. A deposit leg counts
+    /// only the position's collateral and a borrow leg only its debt. Positions that were
+    /// never created are passed as their empty PDA and contribute nothing.
+    pub fn value_obligation(
+        obligation: &Obligation,
+        accounts: &[AccountInfo],
+    ) -> Result<PortfolioValue> {
+        let owner = obligation.owner;
+        let legs: Vec<(Pubkey, ObligationLegSide)> = obligation
+            .legs(ObligationLegSide::Deposit)
+            .into_iter()
+            .map(|pool| (pool, ObligationLegSide::Deposit))
+            .chain(
+                obligation
+                    .legs(ObligationLegSide::Borrow)
+                    .into_iter()
+                    .map(|pool| (pool, ObligationLegSide::Borrow)),
+            )
+            .collect();
+        require_eq!(
+            accounts.len(),
+            legs.len() * ACCOUNTS_PER_OBLIGATION_LEG,
+            LendingError::InvalidPortfolioAccounts
+        );
+        let mut portfolio = PortfolioValue::default();
+
+        for ((pool_key, side), group) in legs
+            .iter()
+            .zip(accounts.chunks(ACCOUNTS_PER_OBLIGATION_LEG))
+        {
+            let (pool_info, position_info, pyth_info, chainlink_info) =
+                (&group[0], &group[1], &group[2], &group[3]);
+            require_keys_eq!(
+                pool_info.key(),
+                *pool_key,
+                LendingError::InvalidPortfolioAccounts
+            );
+
+            if position_info.data_is_empty() {
+                // An empty account only proves there is no position if it is the real PDA.
+                let (expected_position, _) = Pubkey::find_program_address(
+                    &[USER_POSITION_SEED, pool_key.as_ref(), owner.as_ref()],
+                    &crate::ID,
+                );
+                require_keys_eq!(
+                    position_info.key(),
+                    expected_position,
+                    LendingError::InvalidPortfolioAccounts
+                );
+                continue;
+            }
+            let position: UserPosition = read_zero_copy(position_info)?;
+            require_keys_eq!(position.owner, owner, LendingError::InvalidOwner);
+            require_keys_eq!(
+                position.pool,
+                *pool_key,
+                LendingError::InvalidPortfolioAccounts
+            );
+            let scaled_balance = match side {
+                ObligationLegSide::Deposit => position.scaled_collateral,
+                ObligationLegSide::Borrow => position.scaled_loan,
+            };
+            if scaled_balance == 0 {
+                continue;
+            }
+
+            // Project interest up to now on a copy; the pool account itself is read-only here.
+            let mut pool: AssetPool = read_zero_copy(pool_info)?;
+            pool.accrue_interest()?;
+            require_keys_eq!(
+                pyth_info.key(),
+                pool.pyth_price_feed,
+                LendingError::InvalidOracleAccount
+            );
+            require_keys_eq!(
+                chainlink_info.key(),
+                pool.chainlink_price_feed,
+                LendingError::InvalidOracleAccount
+            );
+            let price = oracle::get_price(pyth_info, Some(chainlink_info), pool.oracle_config()?)?;
+            match side {
+                ObligationLegSide::Deposit => portfolio.add_balances(
+                    &pool,
+                    pool.collateral_from_scaled(scaled_balance)?,
+                    0,
+                    price,
+                )?,
+                ObligationLegSide::Borrow => portfolio.add_balances(
+                    &pool,
+                    0,
+                    pool.debt_from_scaled(scaled_balance)?,
+                    price,
+                )?,
+            }
+        }
+        Ok(portfolio)
+    }
+
+    /// Copies a zero-copy program account out of an unchecked `AccountInfo`.
+    pub fn read_zero_copy<T: anchor_lang::ZeroCopy + Owner>(info: &AccountInfo) -> Result<T> {
+        require_keys_eq!(
+            *info.owner,
+            T::owner(),
+            ErrorCode::AccountOwnedByWrongProgram
+        );
+        let data = info.try_borrow_data()?;
+        let disc = T::DISCRIMINATOR;
+        require!(
+            data.len() >= disc.len() + size_of::<T>(),
+            ErrorCode::AccountDidNotDeserialize
+        );
+        require!(
+            &data[..disc.len()] == disc,
+            ErrorCode::AccountDiscriminatorMismatch
+        );
+        Ok(bytemuck::pod_read_unaligned(
+            &data[disc.len()..disc.len() + size_of::<T>()],
+        ))
+    }
+
+    /// Liquidation-threshold-weighted collateral over debt, in basis points (10_000 = 1.0).
+    pub fn health_factor_bps(portfolio: &PortfolioValue) -> Result<u128> {
+        if portfolio.borrowed_value == 0 {
+            return Ok(u128::MAX);
+        }
+        U192::from(portfolio.unhealthy_borrow_value)
+            .checked_mul(U192::from(BASIS_POINTS_DIVISOR))
+            .and_then(|v| v.checked_div(U192::from(portfolio.borrowed_value)))
+            .map(|v| v.min(U192::from(u128::MAX)).as_u128())
+            .ok_or(error!(LendingError::MathOverflow))
+    }
+
+    pub fn is_healthy(portfolio: &PortfolioValue) -> bool {
+        portfolio.borrowed_value <= portfolio.unhealthy_borrow_value
+    }
+    pub fn is_eligible_for_borrow(portfolio: &PortfolioValue) -> bool {
+        portfolio.borrowed_value <= portfolio.allowed_borrow_value
+    }
+    fn apply_bps(value: u128, bps: u64) -> Result<u128> {
+        U192::from(value)
+            .checked_mul(U192::from(bps))
             .and_then(|v| v.checked_div(U192::from(BASIS_POINTS_DIVISOR)))
             .map(|v| v.as_u128())
-            .ok_or(LendingError::MathOverflow)?;
-        Ok(new_debt_value <= max_borrow_value)
+            .ok_or(error!(LendingError::MathOverflow))
     }
-    pub fn calculate_asset_value(amount: u64, price: Price) -> Result<u128> {
+    /// Values `amount` base units of a token with `decimals` decimals, in quote units
+    /// carrying `VALUE_DECIMALS` decimals.
+    pub fn calculate_asset_value(amount: u64, decimals: u8, price: Price) -> Result<u128> {
         let value_precise = U192::from(amount)
             .checked_mul(U192::from(price.price as u128))
             .ok_or(LendingError::MathOverflow)?;
-        let final_value = if price.expo >= 0 {
+        let exponent = price.expo + VALUE_DECIMALS as i32 - decimals as i32;
+        let final_value = if exponent >= 0 {
             value_precise
-                .checked_mul(U192::from(10u128.pow(price.expo as u32)))
+                .checked_mul(U192::from(10u128.pow(exponent as u32)))
                 .ok_or(LendingError::MathOverflow)?
         } else {
             value_precise
-                .checked_div(U192::from(10u128.pow(price.expo.abs() as u32)))
+                .checked_div(U192::from(10u128.pow(exponent.unsigned_abs())))
                 .ok_or(LendingError::MathOverflow)?
         };
-        final_value
-            .checked_div(U192::from(PRECISION_DIVISOR))
-            .map(|v| v.as_u128())
-            .ok_or(error!(LendingError::MathOverflow))
+        require!(
+            final_value <= U192::from(u128::MAX),
+            LendingError::MathOverflow
+        );
+        Ok(final_value.as_u128())
+    }
+    /// Converts an underlying amount into a balance scaled by `index`.
+    pub fn to_scaled(amount: u64, index: u128, round_up: bool) -> Result<u64> {
+        mul_div(amount, INDEX_PRECISION, index, round_up)
+    }
+    /// Converts a scaled balance back into underlying units at `index`.
+    pub fn from_scaled(scaled: u64, index: u128, round_up: bool) -> Result<u64> {
+        mul_div(scaled, index, INDEX_PRECISION, round_up)
     }
-    pub fn calculate_amount_from_value(value: u128, price: Price) -> Result<u64> {
-        let value_scaled = U192::from(value)
-            .checked_mul(U192::from(PRECISION_DIVISOR))
+    fn mul_div(value: u64, numerator: u128, denominator: u128, round_up: bool) -> Result<u64> {
+        let product = U192::from(value)
+            .checked_mul(U192::from(numerator))
             .ok_or(LendingError::MathOverflow)?;
-        let amount_precise = if price.expo >= 0 {
-            value_scaled
-                .checked_div(U192::from(10u128.pow(price.expo as u32)))
+        let product = if round_up {
+            product
+                .checked_add(U192::from(denominator.saturating_sub(1)))
                 .ok_or(LendingError::MathOverflow)?
         } else {
-            value_scaled
-                .checked_mul(U192::from(10u128.pow(price.expo.abs() as u32)))
-                .ok_or(LendingError::MathOverflow)?
+            product
         };
-        amount_precise
-            .checked_div(U192::from(price.price as u128))
-            .map(|v| v.as_u64())
-            .ok_or(error!(LendingError::MathOverflow))
+        let result = product
+            .checked_div(U192::from(denominator))
+            .ok_or(LendingError::MathOverflow)?;
+        require!(result <= U192::from(u64::MAX), LendingError::MathOverflow);
+        Ok(result.as_u64())
+    }
+    /// Inverse of `calculate_asset_value`: the token amount worth `value`.
+    pub fn calculate_amount_from_value(value: u128, decimals: u8, price: Price) -> Result<u64> {
+        let exponent = price.expo + VALUE_DECIMALS as i32 - decimals as i32;
+        let amount_precise = if exponent >= 0 {
+            U192::from(value).checked_div(
+                U192::from(price.price as u128)
+                    .checked_mul(U192::from(10u128.pow(exponent as u32)))
+                    .ok_or(LendingError::MathOverflow)?,
+            )
+        } else {
+            U192::from(value)
+                .checked_mul(U192::from(10u128.pow(exponent.unsigned_abs())))
+                .and_then(|v| v.checked_div(U192::from(price.price as u128)))
+        }
+        .ok_or(LendingError::MathOverflow)?;
+        require!(
+            amount_precise <= U192::from(u64::MAX),
+            LendingError::MathOverflow
+        );
+        Ok(amount_precise.as_u64())
     }
 }
 
@@ -2121,8 +5025,12 @@ pub enum LendingError {
     InsufficientCollateralForLiquidation,
     #[msg("The transaction amount cannot be zero.")]
     ZeroAmount,
-    #[msg("The maximum number of asset pools has been reached.")]
+    #[msg("The pool registry is full; grow it with grow_pool_registry.")]
     MaxAssetsExceeded,
+    #[msg("Too many pool slots for one call; grow the registry in several calls.")]
+    RegistryGrowthTooLarge,
+    #[msg("The asset pool is already listed in this market.")]
+    AssetPoolAlreadyListed,
     #[msg("The asset pool has insufficient liquidity for this operation.")]
     InsufficientLiquidity,
     #[msg("The signer does not match the approved delegatee for this credit line.")]
@@ -2157,10 +5065,90 @@ pub enum LendingError {
     InvalidLiquidationThreshold,
     #[msg("Optimal utilization must be less than 100%.")]
     InvalidOptimalUtilization,
+    #[msg("Liquidation threshold plus bonus must not seize more than 100% of the collateral.")]
+    InvalidLiquidationBonus,
+    #[msg("Close factor must be greater than 0% and at most 100%.")]
+    InvalidCloseFactor,
+    #[msg("Full liquidation health factor must be below 100%.")]
+    InvalidHealthFactor,
     #[msg("The provided asset vault account is invalid for this pool.")]
     InvalidAssetVault,
     #[msg("The provided asset Pool account is invalid for this pool.")]
     InvalidAssetPool,
     #[msg("The provided asset Mint account is invalid for this pool.")]
     InvalidAssetMint,
+    #[msg("The remaining accounts must list one group per obligation leg, in leg order.")]
+    InvalidPortfolioAccounts,
+    #[msg("The obligation must be refreshed in the current slot.")]
+    ObligationStale,
+    #[msg("The pool is already an open leg of this obligation.")]
+    ObligationLegAlreadyOpen,
+    #[msg("The obligation has no free legs left on this side.")]
+    ObligationLegsFull,
+    #[msg("The pool is not an open leg of this obligation.")]
+    ObligationLegNotFound,
+    #[msg("Cannot close an obligation leg whose position still holds a balance.")]
+    ObligationLegNotEmpty,
+    #[msg("Bad debt can only be written off once the obligation's collateral is worth less than the dust value.")]
+    CollateralNotExhausted,
+    #[msg("Protocol and insurance fees together cannot exceed 100% of the interest.")]
+    InvalidFeeSplit,
+    #[msg("The insurance reserve can only be withdrawn while the pool has no outstanding loans.")]
+    InsuranceReserveLocked,
+    #[msg("This deposit would exceed the pool's supply cap.")]
+    SupplyCapExceeded,
+    #[msg("This borrow would exceed the pool's borrow cap.")]
+    BorrowCapExceeded,
+    #[msg("This action is paused on the asset pool.")]
+    PoolActionPaused,
+    #[msg("The pause flags contain unknown bits.")]
+    InvalidPauseFlags,
+    #[msg("This parameter change raises risk and must be queued behind the timelock.")]
+    TimelockRequired,
+    #[msg("The queued parameter change is not executable yet.")]
+    TimelockNotElapsed,
+    #[msg("The parameter change delay can only be raised, up to 30 days.")]
+    InvalidParamChangeDelay,
+    #[msg("The signer is not the proposed governance authority.")]
+    InvalidPendingAuthority,
+    #[msg("There is no pending governance authority transfer.")]
+    NoPendingAuthority,
+    #[msg("The signer does not hold a role that allows this action.")]
+    Unauthorized,
+    #[msg("The asset pool does not belong to this market.")]
+    MarketMismatch,
+    #[msg("The fee collector can only pay out to the fee treasury.")]
+    InvalidFeeTreasury,
+    #[msg(
+        "The asset pool is reduce-only; only withdrawals, repayments and liquidations are allowed."
+    )]
+    PoolReduceOnly,
+    #[msg("The asset pool must be delisted (reduce-only) first.")]
+    PoolNotReduceOnly,
+    #[msg("The asset pool still has deposits or loans outstanding.")]
+    PoolNotEmpty,
+    #[msg("The position still holds collateral or debt.")]
+    PositionNotEmpty,
+    #[msg("Oracle limits must allow a positive price age and at most 100% confidence.")]
+    InvalidOracleLimits,
+    #[msg("Unknown oracle provider.")]
+    InvalidOracleSource,
+    #[msg("Invalid Switchboard feed account provided.")]
+    InvalidSwitchboardAccount,
+    #[msg("The Switchboard price feed is too old.")]
+    SwitchboardPriceTooOld,
+    #[msg("The Switchboard price variance is too wide.")]
+    SwitchboardVarianceTooWide,
+    #[msg("Invalid Switchboard price (e.g., negative or zero).")]
+    InvalidSwitchboardPrice,
+    #[msg("The Pyth price update is for a different feed.")]
+    PythFeedIdMismatch,
+    #[msg("The Pyth price update is not fully verified.")]
+    PythUpdateNotFullyVerified,
+    #[msg("A Pyth pull oracle needs a feed ID.")]
+    MissingPythFeedId,
+    #[msg("The primary and fallback oracle prices deviate too much.")]
+    OracleDeviationTooHigh,
+    #[msg("Oracle deviation must be at most 100% and needs a fallback feed.")]
+    InvalidOracleDeviation,
 }
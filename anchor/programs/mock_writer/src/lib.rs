@@ -1,12 +1,23 @@
 // in programs/mock_writer/src/lib.rs
 
 use anchor_lang::prelude::*;
-use bytemuck;
 use pyth_sdk_solana::state::{
-    AccountType, PriceAccount, PriceInfo, PriceStatus, SolanaPriceAccount, MAGIC, VERSION_2,
+    AccountType, PriceInfo, PriceStatus, SolanaPriceAccount, MAGIC, VERSION_2,
 };
 declare_id!("BN6SmdB35pe6CF8dA7AehZMB3umBGPQK46TcyB9PNBLc");
 
+// Switchboard On-Demand `PullFeedAccountData` layout (byte offsets include the discriminator).
+const SWITCHBOARD_PULL_FEED_DISCRIMINATOR: [u8; 8] = [196, 27, 108, 196, 10, 215, 219, 40];
+const SWITCHBOARD_LAST_UPDATE_TIMESTAMP_OFFSET: usize = 2216;
+const SWITCHBOARD_RESULT_OFFSET: usize = 2264;
+const SWITCHBOARD_RESULT_NUM_SAMPLES_OFFSET: usize = SWITCHBOARD_RESULT_OFFSET + 96;
+const SWITCHBOARD_RESULT_SLOT_OFFSET: usize = SWITCHBOARD_RESULT_OFFSET + 104;
+const SWITCHBOARD_PULL_FEED_MIN_LEN: usize = 2392;
+
+// Pyth pull-oracle `PriceUpdateV2` layout.
+const PYTH_PRICE_UPDATE_V2_DISCRIMINATOR: [u8; 8] = [34, 241, 35, 99, 157, 126, 244, 205];
+const PYTH_PRICE_UPDATE_V2_LEN: usize = 134;
+
 #[program]
 pub mod mock_writer {
     use super::*;
@@ -22,7 +33,7 @@ pub mod mock_writer {
         let mut account_data = account_info.try_borrow_mut_data()?;
 
         // 构建一个完整的 SolanaPriceAccount 实例
-        let mut price_account = SolanaPriceAccount {
+        let price_account = SolanaPriceAccount {
             magic: MAGIC,
             ver: VERSION_2,
             atype: AccountType::Price as u32,
@@ -57,6 +68,79 @@ pub mod mock_writer {
 
         Ok(())
     }
+
+    /// Writes a Switchboard pull-feed result with 18-decimal `value` and `std_dev`, updated now.
+    pub fn create_fake_switchboard(
+        ctx: Context<CreateFakeSwitchboard>,
+        value: i128,
+        std_dev: i128,
+    ) -> Result<()> {
+        let mut account_data = ctx
+            .accounts
+            .fake_switchboard_account
+            .try_borrow_mut_data()?;
+        if account_data.len() < SWITCHBOARD_PULL_FEED_MIN_LEN {
+            return err!(ProgramError::AccountDataTooSmall);
+        }
+        let clock = Clock::get()?;
+
+        account_data[..8].copy_from_slice(&SWITCHBOARD_PULL_FEED_DISCRIMINATOR);
+        account_data[SWITCHBOARD_LAST_UPDATE_TIMESTAMP_OFFSET..][..8]
+            .copy_from_slice(&clock.unix_timestamp.to_le_bytes());
+
+        // value, std_dev, mean, range, min_value, max_value
+        let result = [value, std_dev, value, 0, value, value];
+        for (i, field) in result.iter().enumerate() {
+            account_data[SWITCHBOARD_RESULT_OFFSET + i * 16..][..16]
+                .copy_from_slice(&field.to_le_bytes());
+        }
+        account_data[SWITCHBOARD_RESULT_NUM_SAMPLES_OFFSET] = 1;
+        account_data[SWITCHBOARD_RESULT_SLOT_OFFSET..][..8]
+            .copy_from_slice(&clock.slot.to_le_bytes());
+
+        Ok(())
+    }
+
+    /// Writes a Pyth `PriceUpdateV2` for `feed_id`, published now. Without `fully_verified` the
+    /// update is marked `Partial` with one signature.
+    pub fn create_fake_pyth_pull(
+        ctx: Context<CreateFakePythPull>,
+        feed_id: [u8; 32],
+        price: i64,
+        conf: u64,
+        expo: i32,
+        fully_verified: bool,
+    ) -> Result<()> {
+        let mut account_data = ctx
+            .accounts
+            .fake_price_update_account
+            .try_borrow_mut_data()?;
+        if account_data.len() < PYTH_PRICE_UPDATE_V2_LEN {
+            return err!(ProgramError::AccountDataTooSmall);
+        }
+        let clock = Clock::get()?;
+
+        let mut bytes = Vec::with_capacity(PYTH_PRICE_UPDATE_V2_LEN);
+        bytes.extend_from_slice(&PYTH_PRICE_UPDATE_V2_DISCRIMINATOR);
+        bytes.extend_from_slice(Pubkey::default().as_ref()); // write_authority
+        if fully_verified {
+            bytes.push(1);
+        } else {
+            bytes.extend_from_slice(&[0, 1]);
+        }
+        bytes.extend_from_slice(&feed_id);
+        bytes.extend_from_slice(&price.to_le_bytes());
+        bytes.extend_from_slice(&conf.to_le_bytes());
+        bytes.extend_from_slice(&expo.to_le_bytes());
+        bytes.extend_from_slice(&clock.unix_timestamp.to_le_bytes()); // publish_time
+        bytes.extend_from_slice(&clock.unix_timestamp.to_le_bytes()); // prev_publish_time
+        bytes.extend_from_slice(&price.to_le_bytes()); // ema_price
+        bytes.extend_from_slice(&conf.to_le_bytes()); // ema_conf
+        bytes.extend_from_slice(&clock.slot.to_le_bytes()); // posted_slot
+
+        account_data[..bytes.len()].copy_from_slice(&bytes);
+        Ok(())
+    }
 }
 
 #[derive(Accounts)]
@@ -65,6 +149,20 @@ pub struct CreateFakePyth<'info> {
     #[account(mut)]
     pub fake_pyth_account: AccountInfo<'info>,
 }
+
+#[derive(Accounts)]
+pub struct CreateFakeSwitchboard<'info> {
+    /// CHECK: overwritten with a fixture pull feed
+    #[account(mut)]
+    pub fake_switchboard_account: AccountInfo<'info>,
+}
+
+#[derive(Accounts)]
+pub struct CreateFakePythPull<'info> {
+    /// CHECK: overwritten with a fixture price update
+    #[account(mut)]
+    pub fake_price_update_account: AccountInfo<'info>,
+}
 #[error_code]
 pub enum ProgramError {
     #[msg("The provided account data is too small.")]